@@ -0,0 +1,120 @@
+// ! # blend
+// !
+// ! Composites one layer of imagery over another, so that we can lay hillshade, trails
+// ! or other transparent overlays on top of a base map.
+// !
+
+use image::RgbaImage;
+
+// How a layer's colours combine with the ones beneath it. These follow the W3C
+// "Compositing and Blending" definitions of the same names.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BlendMode {
+    Normal,
+    Multiply,
+    Screen,
+}
+
+impl BlendMode {
+    pub fn from_name(name: &str) -> Option<BlendMode> {
+        match name {
+            "normal" => Some(BlendMode::Normal),
+            "multiply" => Some(BlendMode::Multiply),
+            "screen" => Some(BlendMode::Screen),
+            _ => None,
+        }
+    }
+
+    // Blends a single (0-1) colour channel from the layer with the one beneath it
+    fn blend(&self, backdrop: f32, source: f32) -> f32 {
+        match self {
+            BlendMode::Normal => source,
+            BlendMode::Multiply => backdrop * source,
+            BlendMode::Screen => backdrop + source - backdrop * source,
+        }
+    }
+}
+
+// Composites the top image over the bottom one in place, with the top image's alpha scaled
+// by opacity (0-1). Both images must be the same size.
+pub fn composite(bottom: &mut RgbaImage, top: &RgbaImage, opacity: f32, mode: BlendMode) {
+    debug_assert_eq!(bottom.dimensions(), top.dimensions());
+
+    for (backdrop, source) in bottom.pixels_mut().zip(top.pixels()) {
+        let alpha_s = source[3] as f32 / 255.0 * opacity;
+        if alpha_s <= 0.0 {
+            continue;
+        }
+        let alpha_b = backdrop[3] as f32 / 255.0;
+        let alpha_o = alpha_s + alpha_b * (1.0 - alpha_s);
+
+        for c in 0..3 {
+            let c_s = source[c] as f32 / 255.0;
+            let c_b = backdrop[c] as f32 / 255.0;
+
+            // Where there's nothing beneath us, the layer's own colour shows through unblended
+            let mixed = (1.0 - alpha_b) * c_s + alpha_b * mode.blend(c_b, c_s);
+            let c_o = (alpha_s * mixed + (1.0 - alpha_s) * alpha_b * c_b) / alpha_o;
+            backdrop[c] = (c_o * 255.0).round() as u8;
+        }
+        backdrop[3] = (alpha_o * 255.0).round() as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn blend_pixel(bottom: [u8; 4], top: [u8; 4], opacity: f32, mode: BlendMode) -> [u8; 4] {
+        let mut bottom = RgbaImage::from_pixel(1, 1, Rgba(bottom));
+        let top = RgbaImage::from_pixel(1, 1, Rgba(top));
+        composite(&mut bottom, &top, opacity, mode);
+        bottom.get_pixel(0, 0).0
+    }
+
+    #[test]
+    fn test_normal_blend() {
+        let grey = [128, 128, 128, 255];
+        let red = [255, 0, 0, 255];
+
+        assert_eq!(blend_pixel(grey, red, 1.0, BlendMode::Normal), red);
+        assert_eq!(blend_pixel(grey, red, 0.0, BlendMode::Normal), grey);
+        assert_eq!(
+            blend_pixel(grey, red, 0.25, BlendMode::Normal),
+            [160, 96, 96, 255]
+        );
+
+        // Transparent pixels in the layer leave the backdrop alone, and layers over
+        // nothing keep their own colour
+        assert_eq!(
+            blend_pixel(grey, [255, 0, 0, 0], 1.0, BlendMode::Normal),
+            grey
+        );
+        assert_eq!(
+            blend_pixel([0, 0, 0, 0], red, 0.25, BlendMode::Normal),
+            [255, 0, 0, 64]
+        );
+    }
+
+    #[test]
+    fn test_multiply_and_screen_blend() {
+        let grey = [128, 128, 128, 255];
+        let white = [255, 255, 255, 255];
+        let black = [0, 0, 0, 255];
+
+        // Multiplying by white and screening with black leave the backdrop unchanged
+        assert_eq!(blend_pixel(grey, white, 1.0, BlendMode::Multiply), grey);
+        assert_eq!(blend_pixel(grey, black, 1.0, BlendMode::Screen), grey);
+
+        // ... while multiplying darkens and screening lightens
+        assert_eq!(
+            blend_pixel(grey, grey, 1.0, BlendMode::Multiply),
+            [64, 64, 64, 255]
+        );
+        assert_eq!(
+            blend_pixel(grey, grey, 1.0, BlendMode::Screen),
+            [192, 192, 192, 255]
+        );
+    }
+}
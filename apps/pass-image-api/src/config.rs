@@ -0,0 +1,1319 @@
+// ! # config
+// !
+// ! Settings for talking to tile servers, read at startup from the JSON file named by
+// ! the TILESETS_CONFIG environment variable. For example:
+// !
+// ! {
+// !   "user_agent": {
+// !     "app_name": "pass-image-api",
+// !     "contact_url": "https://example.com/about",
+// !     "email": "maps@example.com"
+// !   },
+// !   "tilesets": {
+// !     "swisstopo": {
+// !       "user_agent": "pass-image-api-swisstopo/1.0",
+// !       "headers": { "Referer": "https://example.com/" },
+// !       "query": { "key": { "env": "SWISSTOPO_API_KEY" } },
+// !       "times": { "1990": "19901231", "2005": "20051231", "2019": "20191231" },
+// !       "layers": {
+// !         "winter": { "id": "ch.swisstopo.landeskarte-farbe-10-winter", "format": "png", "max_zoom": 18 }
+// !       },
+// !       "capabilities": { "url": "https://wmts.geo.admin.ch/EPSG/3857/1.0.0/WMTSCapabilities.xml" }
+// !     },
+// !     "wms": {
+// !       "attribution": "© Example GIS",
+// !       "wms": { "url": "https://gis.example.com/wms", "layers": ["roads"], "crs": "EPSG:3857" }
+// !     },
+// !     "mbtiles": { "mbtiles": "/data/alps.mbtiles" },
+// !     "pmtiles": { "pmtiles": { "url": "http://localhost:8080/alps.pmtiles" } },
+// !     "directory": { "directory": { "root": "/data/tiles", "ext": "jpg" } }
+// !   }
+// ! }
+// !
+// ! Header and query values are either plain strings, { "env": "NAME" } to read an
+// ! environment variable, or { "file": "/path" } to read a file (e.g. a mounted secret).
+// ! Times are the years a tileset with a time dimension has editions for, on top of its
+// ! default (e.g. "current" for swisstopo), each with the time its WMTS server knows that
+// ! edition by. For swisstopo that's the end of the year in its time series layer. Layers add
+// ! to (or replace) the ones a WMTS-style tileset publishes out of the box, by the name
+// ! requests use to pick them. Capabilities
+// ! point at a WMTS GetCapabilities document, as a "url" to fetch at startup or a local
+// ! "file", whose layers we add by their identifiers. The wms tileset only works once it's
+// ! been pointed at a server; see WmsSource for its GetMap parameters. Likewise the mbtiles
+// ! tileset reads from the MBTiles file it's given, whose metadata supplies its zoom range,
+// ! bounds and (unless configured) attribution, and the pmtiles tileset from a PMTiles archive,
+// ! as a local "file" or a "url" that answers range requests. The directory tileset reads
+// ! {root}/{z}/{x}/{y}.{ext} files, serving the zoom levels it finds directories for.
+// !
+
+use crate::coordinates::{TileId, MAX_ZOOM};
+use crate::coverage::Coverage;
+use crate::debug_tiles::Pattern;
+use crate::directory::DirectorySource;
+use crate::mbtiles::MbTiles;
+use crate::pmtiles::{PmTiles, PmTilesSource};
+use crate::template::UrlTemplate;
+use crate::tile_cache::TileCache;
+use crate::tiles::{SourceLayer, TileFormat, TileSet};
+use crate::wms::WmsSource;
+use crate::wmts;
+use anyhow::{anyhow, Context, Result};
+use awc::http::header::{HeaderName, HeaderValue};
+use log::warn;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::{env, fmt, fs};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+// What we show in place of anything we've been configured to send
+pub const REDACTED: &str = "[redacted]";
+
+// How many bytes of upstream tiles we keep in memory. A 512px render at z14 takes around 25
+// tiles of 10-50 KB each, so this holds a good few hundred renders' worth.
+const TILE_CACHE_BYTES: usize = 128 * 1024 * 1024;
+
+// Where a configured value comes from
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ValueSource {
+    Plain(String),
+    Env { env: String },
+    File { file: PathBuf },
+}
+
+impl ValueSource {
+    fn resolve(&self) -> Result<Secret> {
+        match self {
+            ValueSource::Plain(value) => Ok(Secret(value.clone())),
+            ValueSource::Env { env } => env::var(env)
+                .map(Secret)
+                .with_context(|| format!("reading environment variable {}", env)),
+            // Secret files usually end in a newline that isn't part of the secret
+            ValueSource::File { file } => fs::read_to_string(file)
+                .map(|value| Secret(value.trim_end().to_string()))
+                .with_context(|| format!("reading {}", file.display())),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserAgentEntry {
+    app_name: Option<String>,
+    contact_url: Option<String>,
+    email: Option<String>,
+}
+
+// Where to read a WMTS GetCapabilities document from
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CapabilitiesSource {
+    Url(String),
+    File(PathBuf),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SourceLayerEntry {
+    id: String,
+    format: String,
+    #[serde(default)]
+    min_zoom: u32,
+    max_zoom: u32,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TileSetEntry {
+    user_agent: Option<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, ValueSource>,
+    #[serde(default)]
+    query: BTreeMap<String, ValueSource>,
+    #[serde(default)]
+    times: BTreeMap<String, String>,
+    #[serde(default)]
+    layers: BTreeMap<String, SourceLayerEntry>,
+    url_template: Option<String>,
+    #[serde(default)]
+    subdomains: Vec<String>,
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+    capabilities: Option<CapabilitiesSource>,
+    attribution: Option<String>,
+    wms: Option<WmsSource>,
+    mbtiles: Option<PathBuf>,
+    pmtiles: Option<PmTilesSource>,
+    directory: Option<DirectorySource>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    user_agent: UserAgentEntry,
+    #[serde(default)]
+    tilesets: HashMap<String, TileSetEntry>,
+}
+
+// A value we send to a tile server, but never want to see in logs or spans
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+// Extra headers and query parameters to send with every tile request to a tileset, the
+// User-Agent to use in place of our usual one, the years we can ask it for, the layers
+// it publishes on top of its built-in ones, and where its tiles come from if that's up to us
+#[derive(Debug, Default)]
+pub struct TileSetConfig {
+    pub user_agent: Option<String>,
+    pub headers: Vec<(String, Secret)>,
+    pub query: Vec<(String, Secret)>,
+    // Each year we can ask for, with the time value to put in its tile URLs
+    pub times: Vec<(String, String)>,
+    pub layers: Vec<(String, SourceLayer)>,
+    // Replaces the tileset's built-in URL template, with the hosts {s} rotates through and
+    // any variables of our own it fills in
+    pub url_template: Option<String>,
+    pub subdomains: Vec<String>,
+    pub variables: Vec<(String, String)>,
+    // Replaces the tileset's built-in attribution
+    pub attribution: Option<String>,
+    pub wms: Option<WmsSource>,
+    pub mbtiles: Option<MbTiles>,
+    pub pmtiles: Option<PmTiles>,
+    pub directory: Option<DirectorySource>,
+    // Replace the tileset's built-in zoom range and coverage, for sources that describe
+    // themselves
+    pub min_zoom: Option<u32>,
+    pub max_zoom: Option<u32>,
+    pub coverage: Option<Coverage>,
+}
+
+static NO_CONFIG: TileSetConfig = TileSetConfig {
+    user_agent: None,
+    headers: Vec::new(),
+    query: Vec::new(),
+    times: Vec::new(),
+    layers: Vec::new(),
+    url_template: None,
+    subdomains: Vec::new(),
+    variables: Vec::new(),
+    attribution: None,
+    wms: None,
+    mbtiles: None,
+    pmtiles: None,
+    directory: None,
+    min_zoom: None,
+    max_zoom: None,
+    coverage: None,
+};
+
+impl TileSetConfig {
+    // Adds our query parameters onto a tile URL. Returns the URL to request, along with a copy
+    // with the values redacted that's safe to log.
+    pub fn apply_query(&self, url: &str) -> (String, String) {
+        let mut full = url.to_string();
+        let mut redacted = url.to_string();
+
+        for (name, value) in &self.query {
+            let separator = if full.contains('?') { '&' } else { '?' };
+            let name = encode_query_component(name);
+            full.push_str(&format!(
+                "{}{}={}",
+                separator,
+                name,
+                encode_query_component(value.expose())
+            ));
+            redacted.push_str(&format!("{}{}={}", separator, name, REDACTED));
+        }
+
+        (full, redacted)
+    }
+}
+
+// Settings for talking to tile servers
+#[derive(Debug)]
+pub struct Config {
+    user_agent: String,
+    // Who to get in touch with about our traffic, for the From header
+    email: Option<String>,
+    // Whether we gave tile servers any way to contact us
+    has_contact: bool,
+    tilesets: HashMap<TileSet, TileSetConfig>,
+    // Capabilities documents still to be read by load_capabilities
+    capabilities: Vec<(TileSet, CapabilitiesSource)>,
+    // PMTiles archives still to be opened by open_pmtiles
+    pmtiles: Vec<(TileSet, PmTilesSource)>,
+    // The URL template for each tileset and each of its layers, parsed once up front so that
+    // fetching a tile only has to fill one in
+    url_templates: HashMap<(TileSet, Option<SourceLayer>), UrlTemplate>,
+    // The tiles we've fetched from tile servers lately, shared by every render and tile request
+    pub tile_cache: TileCache,
+    // Turns to send each tileset's server a request, so we keep within its limit at once
+    upstream_requests: HashMap<TileSet, Semaphore>,
+}
+
+fn upstream_requests() -> HashMap<TileSet, Semaphore> {
+    TileSet::ALL
+        .iter()
+        .map(|t| (*t, Semaphore::new(t.max_concurrent_requests())))
+        .collect()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let mut config = Config {
+            user_agent: compose_user_agent(env!("CARGO_PKG_NAME"), None),
+            email: None,
+            has_contact: false,
+            tilesets: HashMap::new(),
+            capabilities: Vec::new(),
+            pmtiles: Vec::new(),
+            url_templates: HashMap::new(),
+            tile_cache: TileCache::new(TILE_CACHE_BYTES),
+            upstream_requests: upstream_requests(),
+        };
+        config
+            .parse_url_templates()
+            .expect("The built-in URL templates are checked by TileSet::validate_all");
+        config
+    }
+}
+
+impl Config {
+    // Reads the file named by TILESETS_CONFIG, if there is one
+    pub fn from_env() -> Result<Config> {
+        match env::var("TILESETS_CONFIG") {
+            Ok(path) => {
+                let json =
+                    fs::read_to_string(&path).with_context(|| format!("reading {}", path))?;
+                Config::from_json(&json).with_context(|| format!("loading {}", path))
+            }
+            Err(_) => Ok(Config::default()),
+        }
+    }
+
+    // Parses the config, resolving all of the values and checking they'll make valid headers
+    pub fn from_json(json: &str) -> Result<Config> {
+        let file: ConfigFile = serde_json::from_str(json)?;
+
+        let UserAgentEntry {
+            app_name,
+            contact_url,
+            email,
+        } = file.user_agent;
+        let user_agent = compose_user_agent(
+            app_name.as_deref().unwrap_or(env!("CARGO_PKG_NAME")),
+            contact_url.as_deref(),
+        );
+        HeaderValue::from_str(&user_agent)
+            .with_context(|| format!("invalid User-Agent {}", user_agent))?;
+        if let Some(email) = &email {
+            HeaderValue::from_str(email).with_context(|| format!("invalid email {}", email))?;
+        }
+
+        let mut tilesets = HashMap::new();
+        let mut capabilities = Vec::new();
+        let mut pmtiles = Vec::new();
+        for (name, entry) in file.tilesets {
+            let tileset =
+                TileSet::from_name(&name).ok_or_else(|| anyhow!("Unknown tileset '{}'", name))?;
+
+            let mut headers = Vec::new();
+            for (header, source) in &entry.headers {
+                let value = source
+                    .resolve()
+                    .with_context(|| format!("resolving header {} for {}", header, name))?;
+                HeaderName::from_bytes(header.as_bytes())
+                    .with_context(|| format!("invalid header name {} for {}", header, name))?;
+                HeaderValue::from_str(value.expose())
+                    .with_context(|| format!("invalid value for header {} for {}", header, name))?;
+                headers.push((header.clone(), value));
+            }
+
+            let mut query = Vec::new();
+            for (param, source) in &entry.query {
+                let value = source
+                    .resolve()
+                    .with_context(|| format!("resolving query parameter {} for {}", param, name))?;
+                query.push((param.clone(), value));
+            }
+
+            if let Some(user_agent) = &entry.user_agent {
+                HeaderValue::from_str(user_agent)
+                    .with_context(|| format!("invalid User-Agent for {}", name))?;
+            }
+
+            if !entry.times.is_empty() && !tileset.supports_time() {
+                return Err(anyhow!("{} doesn't have a time dimension", name));
+            }
+            if let Some(time) = entry.times.keys().find(|time| !is_year(time)) {
+                return Err(anyhow!(
+                    "invalid time '{}' for {}, expected YYYY",
+                    time,
+                    name
+                ));
+            }
+            if let Some((time, value)) = entry
+                .times
+                .iter()
+                .find(|(_, value)| value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()))
+            {
+                return Err(anyhow!(
+                    "invalid time value '{}' for {} of {}, expected e.g. {}1231",
+                    value,
+                    time,
+                    name,
+                    time
+                ));
+            }
+
+            if (entry.url_template.is_some()
+                || !entry.subdomains.is_empty()
+                || !entry.variables.is_empty())
+                && !tileset.fetches_from_urls()
+            {
+                return Err(anyhow!("{} doesn't fetch its tiles from URLs", name));
+            }
+            // {r} would always be empty, since we never ask the tileset for retina tiles
+            if entry.url_template.as_ref().is_some_and(|t| t.contains("{r}"))
+                && !tileset.supports_retina()
+            {
+                return Err(anyhow!(
+                    "{} doesn't serve retina tiles, so its URL template can't use {{r}}",
+                    name
+                ));
+            }
+            if entry.subdomains.iter().any(String::is_empty) {
+                return Err(anyhow!("empty subdomain for {}", name));
+            }
+            // ... nor can variables stand in for the ones we fill in, which would win anyway
+            if let Some(variable) = entry.variables.keys().find(|v| {
+                UrlTemplate::is_built_in(v) || v.as_str() == "layer" || v.as_str() == "ext"
+            }) {
+                return Err(anyhow!(
+                    "variable {{{}}} of {} is already one of the URL template's",
+                    variable,
+                    name
+                ));
+            }
+
+            if !entry.layers.is_empty() && !tileset.supports_layers() {
+                return Err(anyhow!("{} doesn't have layers to pick from", name));
+            }
+            let mut layers = Vec::new();
+            for (layer, source) in entry.layers {
+                let format = TileFormat::from_name(&source.format).ok_or_else(|| {
+                    anyhow!(
+                        "invalid format '{}' for layer {} of {}, expected png, jpeg or webp",
+                        source.format,
+                        layer,
+                        name
+                    )
+                })?;
+                if source.min_zoom > source.max_zoom || source.max_zoom > MAX_ZOOM {
+                    return Err(anyhow!(
+                        "invalid zoom range {}-{} for layer {} of {}",
+                        source.min_zoom,
+                        source.max_zoom,
+                        layer,
+                        name
+                    ));
+                }
+                // The debug tileset draws its layers, so they can only be patterns we know
+                if tileset == TileSet::Debug && Pattern::from_name(&source.id).is_none() {
+                    return Err(anyhow!(
+                        "invalid id '{}' for layer {} of {}, expected checkerboard or gradient",
+                        source.id,
+                        layer,
+                        name
+                    ));
+                }
+                let source = SourceLayer::new(&source.id, format, source.min_zoom, source.max_zoom);
+                layers.push((layer, source));
+            }
+
+            if let Some(source) = entry.capabilities {
+                if !tileset.supports_layers() || tileset == TileSet::Debug {
+                    return Err(anyhow!(
+                        "{} doesn't have layers to read capabilities for",
+                        name
+                    ));
+                }
+                capabilities.push((tileset, source));
+            }
+
+            if let Some(wms) = &entry.wms {
+                if tileset != TileSet::Wms {
+                    return Err(anyhow!("{} can't be a WMS source", name));
+                }
+                wms.validate()
+                    .with_context(|| format!("checking the WMS source for {}", name))?;
+            }
+
+            let mbtiles = match &entry.mbtiles {
+                Some(_) if tileset != TileSet::MbTiles => {
+                    return Err(anyhow!("{} can't read from an MBTiles file", name));
+                }
+                Some(path) => Some(
+                    MbTiles::open(path)
+                        .with_context(|| format!("opening the MBTiles file for {}", name))?,
+                ),
+                None => None,
+            };
+            if let Some(source) = entry.pmtiles {
+                if tileset != TileSet::PmTiles {
+                    return Err(anyhow!("{} can't read from a PMTiles archive", name));
+                }
+                pmtiles.push((tileset, source));
+            }
+
+            let metadata = mbtiles
+                .as_ref()
+                .map(|m| m.metadata.clone())
+                .unwrap_or_default();
+
+            let (min_zoom, max_zoom) = match &entry.directory {
+                Some(_) if tileset != TileSet::Directory => {
+                    return Err(anyhow!("{} can't read from a directory", name));
+                }
+                Some(directory) => {
+                    directory
+                        .validate()
+                        .with_context(|| format!("checking the directory for {}", name))?;
+                    let zooms = directory.zoom_levels()?;
+                    (zooms.first().copied(), zooms.last().copied())
+                }
+                None => (metadata.min_zoom, metadata.max_zoom),
+            };
+
+            let tileset_config = TileSetConfig {
+                user_agent: entry.user_agent,
+                headers,
+                query,
+                times: entry.times.into_iter().collect(),
+                layers,
+                url_template: entry.url_template,
+                subdomains: entry.subdomains,
+                variables: entry.variables.into_iter().collect(),
+                attribution: entry.attribution.or(metadata.attribution),
+                wms: entry.wms,
+                mbtiles,
+                pmtiles: None,
+                directory: entry.directory,
+                min_zoom,
+                max_zoom,
+                coverage: metadata.bounds.map(Coverage::Bbox),
+            };
+            for (layer, source) in &tileset_config.layers {
+                tileset
+                    .url_template(Some(source), &tileset_config)
+                    .with_context(|| format!("checking layer {} of {}", layer, name))?;
+            }
+            tilesets.insert(tileset, tileset_config);
+        }
+
+        let mut config = Config {
+            user_agent,
+            has_contact: contact_url.is_some() || email.is_some(),
+            email,
+            tilesets,
+            capabilities,
+            pmtiles,
+            url_templates: HashMap::new(),
+            tile_cache: TileCache::new(TILE_CACHE_BYTES),
+            upstream_requests: upstream_requests(),
+        };
+        config.parse_url_templates()?;
+        Ok(config)
+    }
+
+    // Waits for a turn to send a request to the tileset's server, which lasts as long as the
+    // permit is held
+    pub async fn upstream_permit(&self, tileset: TileSet) -> Result<SemaphorePermit<'_>> {
+        self.upstream_requests
+            .get(&tileset)
+            .ok_or_else(|| anyhow!("No request limit for {}", tileset.name()))?
+            .acquire()
+            .await
+            .map_err(|_| anyhow!("{} isn't taking requests any more", tileset.name()))
+    }
+
+    // Parses the URL template for every tileset and every layer we know of for it
+    fn parse_url_templates(&mut self) -> Result<()> {
+        let mut url_templates = HashMap::new();
+        for tileset in TileSet::ALL {
+            let sources = tileset
+                .built_in_layers()
+                .into_iter()
+                .chain(tileset.time_series_source().map(|source| ("", source)))
+                .map(|(_, source)| Some(source))
+                .chain(
+                    self.tileset(tileset)
+                        .layers
+                        .iter()
+                        .map(|(_, source)| Some(source.clone())),
+                );
+            for source in std::iter::once(None).chain(sources) {
+                let template = tileset.url_template(source.as_ref(), self.tileset(tileset))?;
+                url_templates.insert((tileset, source), template);
+            }
+        }
+        self.url_templates = url_templates;
+        Ok(())
+    }
+
+    // The URL template for the tileset's source layer, or its default one
+    pub fn url_template(
+        &self,
+        tileset: TileSet,
+        source: Option<&SourceLayer>,
+    ) -> Result<&UrlTemplate> {
+        self.url_templates
+            .get(&(tileset, source.cloned()))
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} has no layer {}",
+                    tileset.name(),
+                    source.map_or("", |s| s.id.as_str())
+                )
+            })
+    }
+
+    // Reads the GetCapabilities documents in the config, fetching any that are URLs, and adds
+    // the layers in them to their tilesets. Layers configured by hand win over ones of the
+    // same name, and layers we can't build tile URLs for are skipped.
+    pub async fn load_capabilities(&mut self) -> Result<()> {
+        for (tileset, source) in std::mem::take(&mut self.capabilities) {
+            let xml = match &source {
+                CapabilitiesSource::Url(url) => wmts::fetch_capabilities(self, tileset, url).await,
+                CapabilitiesSource::File(file) => {
+                    fs::read_to_string(file).with_context(|| format!("reading {}", file.display()))
+                }
+            }
+            .with_context(|| format!("loading capabilities for {}", tileset.name()))?;
+            let found = wmts::parse_capabilities(&xml)
+                .with_context(|| format!("parsing capabilities for {}", tileset.name()))?;
+
+            let tileset_config = self.tilesets.entry(tileset).or_default();
+            for (name, source) in found {
+                if let Err(e) = tileset.url_template(Some(&source), tileset_config) {
+                    warn!(
+                        "Skipping layer {0} of {1} from its capabilities: {2:#}",
+                        name,
+                        tileset.name(),
+                        e
+                    );
+                    continue;
+                }
+                if !tileset_config.layers.iter().any(|(n, _)| *n == name) {
+                    tileset_config.layers.push((name, source));
+                }
+            }
+        }
+        self.parse_url_templates()
+    }
+
+    // Opens the PMTiles archives in the config, reading their headers and root directories, and
+    // fills in their tilesets' zoom ranges, bounds and (unless configured) attribution
+    pub async fn open_pmtiles(&mut self) -> Result<()> {
+        for (tileset, source) in std::mem::take(&mut self.pmtiles) {
+            let archive = PmTiles::open(source, self)
+                .await
+                .with_context(|| format!("opening the PMTiles archive for {}", tileset.name()))?;
+
+            let tileset_config = self.tilesets.entry(tileset).or_default();
+            tileset_config.min_zoom = Some(archive.header.min_zoom);
+            tileset_config.max_zoom = Some(archive.header.max_zoom);
+            tileset_config.coverage = Some(Coverage::Bbox(archive.header.bounds));
+            if tileset_config.attribution.is_none() {
+                tileset_config.attribution = archive.attribution.clone();
+            }
+            tileset_config.pmtiles = Some(archive);
+        }
+        Ok(())
+    }
+
+    pub fn tileset(&self, tileset: TileSet) -> &TileSetConfig {
+        self.tilesets.get(&tileset).unwrap_or(&NO_CONFIG)
+    }
+
+    // Whether we can fetch tiles from the tileset at all. Some only work once the config
+    // tells us where to find them.
+    pub fn is_available(&self, tileset: TileSet) -> bool {
+        match tileset {
+            TileSet::Wms => self.tileset(tileset).wms.is_some(),
+            TileSet::MbTiles => self.tileset(tileset).mbtiles.is_some(),
+            TileSet::PmTiles => self.tileset(tileset).pmtiles.is_some(),
+            TileSet::Directory => self.tileset(tileset).directory.is_some(),
+            _ => true,
+        }
+    }
+
+    pub fn min_zoom(&self, tileset: TileSet) -> u32 {
+        self.tileset(tileset).min_zoom.unwrap_or(0)
+    }
+
+    // The deepest zoom level the tileset serves tiles for
+    pub fn max_zoom(&self, tileset: TileSet) -> u32 {
+        self.tileset(tileset).max_zoom.unwrap_or(tileset.max_zoom())
+    }
+
+    // The area the tileset has imagery for
+    pub fn coverage(&self, tileset: TileSet) -> Coverage {
+        self.tileset(tileset)
+            .coverage
+            .clone()
+            .unwrap_or_else(|| tileset.coverage())
+    }
+
+    // Whether we should bother asking the tileset for a tile: it has to serve the zoom level,
+    // and the tile has to overlap its coverage
+    pub fn serves(&self, tileset: TileSet, tile: TileId) -> bool {
+        (self.min_zoom(tileset)..=self.max_zoom(tileset)).contains(&tile.z)
+            && self.coverage(tileset).intersects(tile.bbox_wgs84())
+    }
+
+    // The attribution to display alongside the tileset's imagery
+    pub fn attribution(&self, tileset: TileSet) -> &str {
+        self.tileset(tileset)
+            .attribution
+            .as_deref()
+            .unwrap_or(tileset.attribution())
+    }
+
+    pub fn user_agent(&self, tileset: TileSet) -> &str {
+        self.tileset(tileset)
+            .user_agent
+            .as_deref()
+            .unwrap_or(&self.user_agent)
+    }
+
+    // What to send in the From header to the tileset, for those whose usage policies want
+    // a way to reach us
+    pub fn contact_email(&self, tileset: TileSet) -> Option<&str> {
+        match tileset.requires_contact() {
+            true => self.email.as_deref(),
+            false => None,
+        }
+    }
+
+    // The times we can render the tileset at: its default, followed by any configured years.
+    // Empty for tilesets without a time dimension.
+    pub fn available_times(&self, tileset: TileSet) -> Vec<&str> {
+        tileset
+            .default_time()
+            .into_iter()
+            .chain(
+                self.tileset(tileset)
+                    .times
+                    .iter()
+                    .map(|(year, _)| year.as_str()),
+            )
+            .collect()
+    }
+
+    // The time value in the tile URLs for one of the tileset's configured years
+    pub fn time_value(&self, tileset: TileSet, year: &str) -> Option<&str> {
+        self.tileset(tileset)
+            .times
+            .iter()
+            .find(|(y, _)| y == year)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Looks up one of the tileset's layers by name, preferring the configured ones over
+    // the built-in ones
+    pub fn source_layer(&self, tileset: TileSet, name: &str) -> Option<SourceLayer> {
+        let configured = self
+            .tileset(tileset)
+            .layers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, source)| source.clone());
+
+        configured.or_else(|| {
+            tileset
+                .built_in_layers()
+                .into_iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, source)| source)
+        })
+    }
+
+    // The names of all of the layers we can pick from the tileset, built-in ones first
+    pub fn source_layer_names(&self, tileset: TileSet) -> Vec<String> {
+        let mut names: Vec<String> = tileset
+            .built_in_layers()
+            .into_iter()
+            .map(|(name, _)| name.to_string())
+            .collect();
+        for (name, _) in &self.tileset(tileset).layers {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names
+    }
+
+    // The tilesets whose usage policies want contact details we haven't configured. Only a
+    // contact URL or email will do; a tileset's own User-Agent doesn't say how to reach us.
+    pub fn missing_contact(&self) -> Vec<TileSet> {
+        TileSet::ALL
+            .into_iter()
+            .filter(|t| t.requires_contact() && !self.has_contact)
+            .collect()
+    }
+}
+
+// Builds a User-Agent like "pass-image-api/0.1.1 (+https://example.com/about)", which
+// identifies us and, if we have one, where to find out more
+fn compose_user_agent(app_name: &str, contact_url: Option<&str>) -> String {
+    match contact_url {
+        Some(url) => format!("{}/{} (+{})", app_name, env!("CARGO_PKG_VERSION"), url),
+        None => format!("{}/{}", app_name, env!("CARGO_PKG_VERSION")),
+    }
+}
+
+fn is_year(time: &str) -> bool {
+    time.len() == 4 && time.bytes().all(|b| b.is_ascii_digit())
+}
+
+// Percent-encodes everything but the unreserved characters from RFC 3986
+pub fn encode_query_component(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiles::Layer;
+
+    #[test]
+    fn test_config_from_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret_file = dir.path().join("secret");
+        fs::write(&secret_file, "from-a-file\n").unwrap();
+        env::set_var("PASS_IMAGE_API_TEST_KEY", "from-the-env");
+
+        let json = format!(
+            r#"{{
+                "tilesets": {{
+                    "swisstopo": {{
+                        "headers": {{
+                            "Referer": "https://example.com/",
+                            "Authorization": {{ "file": "{0}" }}
+                        }},
+                        "query": {{ "key": {{ "env": "PASS_IMAGE_API_TEST_KEY" }} }}
+                    }}
+                }}
+            }}"#,
+            secret_file.display()
+        );
+        let config = Config::from_json(&json).expect("I can load my config");
+
+        let swisstopo = config.tileset(TileSet::Swisstopo);
+        let headers: Vec<(&str, &str)> = swisstopo
+            .headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.expose()))
+            .collect();
+        assert_eq!(
+            headers,
+            vec![
+                ("Authorization", "from-a-file"),
+                ("Referer", "https://example.com/")
+            ]
+        );
+        assert_eq!(swisstopo.query[0].1.expose(), "from-the-env");
+
+        // Nothing configured means nothing extra to send
+        assert!(config.tileset(TileSet::Osm).headers.is_empty());
+
+        // ... and secrets stay secret when we print the config
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("from-the-env"));
+        assert!(!debug.contains("from-a-file"));
+    }
+
+    #[test]
+    fn test_user_agent() {
+        let default = Config::default();
+        assert_eq!(
+            default.user_agent(TileSet::Osm),
+            format!("pass-image-api/{}", env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(default.contact_email(TileSet::Osm), None);
+        assert_eq!(default.missing_contact(), vec![TileSet::Osm]);
+
+        let config = Config::from_json(
+            r#"{
+                "user_agent": {
+                    "app_name": "pass-finder",
+                    "contact_url": "https://example.com/about",
+                    "email": "maps@example.com"
+                },
+                "tilesets": { "swisstopo": { "user_agent": "pass-finder-swisstopo/2.0" } }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.user_agent(TileSet::Osm),
+            format!(
+                "pass-finder/{} (+https://example.com/about)",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        assert_eq!(
+            config.user_agent(TileSet::Swisstopo),
+            "pass-finder-swisstopo/2.0"
+        );
+
+        // Only tilesets that ask for contact details get the From header
+        assert_eq!(config.contact_email(TileSet::Osm), Some("maps@example.com"));
+        assert_eq!(config.contact_email(TileSet::Swisstopo), None);
+        assert!(config.missing_contact().is_empty());
+
+        // Naming ourselves to OSM isn't the same as giving it a way to reach us
+        let config =
+            Config::from_json(r#"{ "tilesets": { "osm": { "user_agent": "pass-finder/2.0" } } }"#)
+                .unwrap();
+        assert_eq!(config.missing_contact(), vec![TileSet::Osm]);
+    }
+
+    #[test]
+    fn test_config_rejects_bad_values() {
+        for json in [
+            r#"{ "tilesets": { "nope": {} } }"#,
+            r#"{ "tilesets": { "osm": { "headers": { "X-Key": { "env": "PASS_IMAGE_API_NOT_SET" } } } } }"#,
+            r#"{ "tilesets": { "osm": { "headers": { "Bad Header": "value" } } } }"#,
+            r#"{ "tilesets": { "osm": { "cookies": {} } } }"#,
+            r#"{ "user_agent": { "app_name": "bad\nname" } }"#,
+            r#"{ "tilesets": { "osm": { "times": { "2019": "20191231" } } } }"#,
+            r#"{ "tilesets": { "swisstopo": { "times": { "19": "19191231" } } } }"#,
+            r#"{ "tilesets": { "swisstopo": { "times": { "2019": "../current" } } } }"#,
+            r#"{ "tilesets": { "swisstopo": { "times": ["2019"] } } }"#,
+            r#"{ "tilesets": { "osm": { "layers": { "a": { "id": "a", "format": "png", "max_zoom": 18 } } } } }"#,
+            r#"{ "tilesets": { "swisstopo": { "layers": { "a": { "id": "a", "format": "gif", "max_zoom": 18 } } } } }"#,
+            r#"{ "tilesets": { "swisstopo": { "layers": { "a": { "id": "a", "format": "png", "min_zoom": 10, "max_zoom": 8 } } } } }"#,
+            r#"{ "tilesets": { "debug": { "layers": { "a": { "id": "stripes", "format": "png", "max_zoom": 18 } } } } }"#,
+            r#"{ "tilesets": { "debug": { "capabilities": { "file": "/capabilities.xml" } } } }"#,
+        ] {
+            assert!(Config::from_json(json).is_err(), "{json}");
+        }
+
+        // Debug layers can still be configured under other names, as long as they're patterns
+        assert!(Config::from_json(
+            r#"{ "tilesets": { "debug": { "layers": { "boxes": { "id": "checkerboard", "format": "png", "max_zoom": 18 } } } } }"#
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_available_times() {
+        let config =
+            Config::from_json(r#"{ "tilesets": { "swisstopo": { "times": { "1990": "19901231", "2019": "20191231" } } } }"#)
+                .unwrap();
+
+        assert_eq!(
+            config.available_times(TileSet::Swisstopo),
+            vec!["current", "1990", "2019"]
+        );
+        assert!(config.available_times(TileSet::Osm).is_empty());
+        assert_eq!(
+            config.time_value(TileSet::Swisstopo, "1990"),
+            Some("19901231")
+        );
+        assert_eq!(config.time_value(TileSet::Swisstopo, "2005"), None);
+        assert_eq!(
+            Config::default().available_times(TileSet::Swisstopo),
+            vec!["current"]
+        );
+    }
+
+    #[test]
+    fn test_source_layers() {
+        let config = Config::from_json(
+            r#"{
+                "tilesets": {
+                    "swisstopo": {
+                        "layers": {
+                            "winter": { "id": "ch.swisstopo.landeskarte-farbe-10-winter", "format": "png", "max_zoom": 17 },
+                            "swissimage": { "id": "ch.swisstopo.swissimage", "format": "jpeg", "min_zoom": 8, "max_zoom": 19 }
+                        }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let winter = config.source_layer(TileSet::Swisstopo, "winter").unwrap();
+        assert_eq!(winter.id, "ch.swisstopo.landeskarte-farbe-10-winter");
+        assert_eq!((winter.min_zoom, winter.max_zoom), (0, 17));
+
+        // Configured layers replace built-in ones of the same name
+        let aerial = config
+            .source_layer(TileSet::Swisstopo, "swissimage")
+            .unwrap();
+        assert_eq!((aerial.format, aerial.max_zoom), (TileFormat::Jpeg, 19));
+        assert!(config.source_layer(TileSet::Swisstopo, "hiking").is_some());
+        assert!(config.source_layer(TileSet::Osm, "winter").is_none());
+
+        assert_eq!(
+            config.source_layer_names(TileSet::Swisstopo),
+            vec!["colour", "grey", "swissimage", "hiking", "winter"]
+        );
+
+        // Their URL templates are ready to fill in, and only theirs
+        let tile = TileId {
+            z: 8,
+            x: 133,
+            y: 89,
+        };
+        let url = config
+            .url_template(TileSet::Swisstopo, Some(&winter))
+            .unwrap()
+            .render(tile, false, None);
+        assert!(
+            url.contains(
+                "/ch.swisstopo.landeskarte-farbe-10-winter/default/current/3857/8/133/89.png"
+            ),
+            "{url}"
+        );
+        let unknown = SourceLayer::new("nope", TileFormat::Png, 0, 18);
+        assert!(config
+            .url_template(TileSet::Swisstopo, Some(&unknown))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_capabilities_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let capabilities = dir.path().join("capabilities.xml");
+        fs::write(
+            &capabilities,
+            r#"<Capabilities xmlns="http://www.opengis.net/wmts/1.0" xmlns:ows="http://www.opengis.net/ows/1.1">
+              <Contents>
+                <Layer>
+                  <ows:Identifier>ch.swisstopo.pixelkarte-farbe-winter</ows:Identifier>
+                  <TileMatrixSetLink><TileMatrixSet>3857</TileMatrixSet></TileMatrixSetLink>
+                  <ResourceURL format="image/jpeg" resourceType="tile"
+                    template="https://example.com/{TileMatrixSet}/{TileMatrix}/{TileCol}/{TileRow}.jpeg"/>
+                </Layer>
+                <TileMatrixSet>
+                  <ows:Identifier>3857</ows:Identifier>
+                  <ows:SupportedCRS>EPSG:3857</ows:SupportedCRS>
+                  <TileMatrix><ows:Identifier>0</ows:Identifier></TileMatrix>
+                  <TileMatrix><ows:Identifier>17</ows:Identifier></TileMatrix>
+                </TileMatrixSet>
+              </Contents>
+            </Capabilities>"#,
+        )
+        .unwrap();
+
+        let mut config = Config::from_json(&format!(
+            r#"{{ "tilesets": {{ "swisstopo": {{ "capabilities": {{ "file": "{}" }} }} }} }}"#,
+            capabilities.display()
+        ))
+        .unwrap();
+        assert!(config
+            .source_layer(TileSet::Swisstopo, "ch.swisstopo.pixelkarte-farbe-winter")
+            .is_none());
+
+        config.load_capabilities().await.unwrap();
+        let winter = config
+            .source_layer(TileSet::Swisstopo, "ch.swisstopo.pixelkarte-farbe-winter")
+            .expect("The layer from the capabilities is there");
+        assert_eq!((winter.format, winter.max_zoom), (TileFormat::Jpeg, 17));
+
+        // The layer has no Time dimension, so there's no edition to ask it for
+        let mut layer = Layer::opaque(vec![TileSet::Swisstopo]);
+        layer
+            .select_source(Some("ch.swisstopo.pixelkarte-farbe-winter"), &config)
+            .unwrap();
+        assert!(!layer.select_time("1990", &config).unwrap());
+        assert_eq!(layer.time, None);
+
+        // OSM isn't a WMTS
+        assert!(Config::from_json(
+            r#"{ "tilesets": { "osm": { "capabilities": { "file": "/nope.xml" } } } }"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_wms_config() {
+        assert!(!Config::default().is_available(TileSet::Wms));
+        assert_eq!(Config::default().attribution(TileSet::Wms), "");
+
+        let config = Config::from_json(
+            r#"{
+                "tilesets": {
+                    "wms": {
+                        "attribution": "© Example GIS",
+                        "wms": { "url": "https://gis.example.com/wms", "layers": ["roads"] }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert!(config.is_available(TileSet::Wms));
+        assert_eq!(config.attribution(TileSet::Wms), "© Example GIS");
+        assert_eq!(
+            config.attribution(TileSet::Osm),
+            "© OpenStreetMap contributors"
+        );
+
+        for (json, expected) in [
+            (
+                r#"{ "tilesets": { "osm": { "wms": { "url": "https://example.com/wms", "layers": ["a"] } } } }"#,
+                "osm can't be a WMS source",
+            ),
+            (
+                r#"{ "tilesets": { "wms": { "wms": { "url": "https://example.com/wms", "layers": [] } } } }"#,
+                "WMS sources need at least one layer",
+            ),
+        ] {
+            let err = Config::from_json(json).unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{err:#}");
+        }
+    }
+
+    #[test]
+    fn test_mbtiles_config() {
+        assert!(!Config::default().is_available(TileSet::MbTiles));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiles.mbtiles");
+        crate::mbtiles::tests::write_test_mbtiles(&path);
+        let json = format!(
+            r#"{{ "tilesets": {{ "mbtiles": {{ "mbtiles": {} }} }} }}"#,
+            serde_json::to_string(&path).unwrap()
+        );
+        let config = Config::from_json(&json).unwrap();
+
+        // The file's metadata fills in the registry
+        assert!(config.is_available(TileSet::MbTiles));
+        assert_eq!(config.attribution(TileSet::MbTiles), "© Test");
+        assert_eq!(config.min_zoom(TileSet::MbTiles), 1);
+        assert_eq!(config.max_zoom(TileSet::MbTiles), 3);
+        assert!(config.serves(TileSet::MbTiles, TileId { z: 2, x: 2, y: 1 }));
+        assert!(!config.serves(TileSet::MbTiles, TileId { z: 0, x: 0, y: 0 }));
+        assert!(!config.serves(TileSet::MbTiles, TileId { z: 4, x: 8, y: 4 }));
+        // ... which only covers the north-east quarter of the world
+        assert!(!config.serves(TileSet::MbTiles, TileId { z: 2, x: 1, y: 1 }));
+
+        for (json, expected) in [
+            (
+                r#"{ "tilesets": { "osm": { "mbtiles": "/nope.mbtiles" } } }"#,
+                "osm can't read from an MBTiles file",
+            ),
+            (
+                r#"{ "tilesets": { "mbtiles": { "mbtiles": "/nope.mbtiles" } } }"#,
+                "opening the MBTiles file for mbtiles",
+            ),
+        ] {
+            let err = Config::from_json(json).unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{err:#}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pmtiles_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiles.pmtiles");
+        crate::pmtiles::tests::write_test_pmtiles(&path);
+        let json = format!(
+            r#"{{ "tilesets": {{ "pmtiles": {{ "pmtiles": {{ "file": {} }} }} }} }}"#,
+            serde_json::to_string(&path).unwrap()
+        );
+        let mut config = Config::from_json(&json).unwrap();
+        assert!(!config.is_available(TileSet::PmTiles));
+
+        // Opening the archive fills in the registry from its header and metadata
+        config.open_pmtiles().await.unwrap();
+        assert!(config.is_available(TileSet::PmTiles));
+        assert_eq!(config.attribution(TileSet::PmTiles), "© Test");
+        assert_eq!(config.max_zoom(TileSet::PmTiles), 2);
+        assert!(config.serves(TileSet::PmTiles, TileId { z: 1, x: 1, y: 0 }));
+        assert!(!config.serves(TileSet::PmTiles, TileId { z: 1, x: 0, y: 0 }));
+
+        let err = Config::from_json(
+            r#"{ "tilesets": { "osm": { "pmtiles": { "file": "/nope.pmtiles" } } } }"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("osm can't read from a PMTiles archive"), "{err}");
+        let mut missing = Config::from_json(
+            r#"{ "tilesets": { "pmtiles": { "pmtiles": { "file": "/nope.pmtiles" } } } }"#,
+        )
+        .unwrap();
+        let err = missing.open_pmtiles().await.unwrap_err();
+        assert!(
+            format!("{err:#}").contains("opening the PMTiles archive for pmtiles"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn test_directory_config() {
+        assert!(!Config::default().is_available(TileSet::Directory));
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for z in ["7", "9"] {
+            fs::create_dir_all(root.join(z)).unwrap();
+        }
+        let json = format!(
+            r#"{{ "tilesets": {{ "directory": {{ "directory": {{ "root": {} }} }} }} }}"#,
+            serde_json::to_string(&root).unwrap()
+        );
+        let config = Config::from_json(&json).unwrap();
+
+        // The zoom levels come from the directories
+        assert!(config.is_available(TileSet::Directory));
+        assert_eq!(config.min_zoom(TileSet::Directory), 7);
+        assert_eq!(config.max_zoom(TileSet::Directory), 9);
+
+        for (json, expected) in [
+            (
+                r#"{ "tilesets": { "osm": { "directory": { "root": "/" } } } }"#,
+                "osm can't read from a directory",
+            ),
+            (
+                r#"{ "tilesets": { "directory": { "directory": { "root": "/nope" } } } }"#,
+                "/nope isn't a directory",
+            ),
+        ] {
+            let err = Config::from_json(json).unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{err:#}");
+        }
+    }
+
+    #[test]
+    fn test_url_template_config() {
+        let config = Config::from_json(
+            r#"{
+                "tilesets": {
+                    "osm": {
+                        "url_template": "https://{s}.tiles.example.com/{z}/{x}/{-y}.png?q={q}&key={key}",
+                        "subdomains": ["a", "b", "c"],
+                        "variables": { "key": "abc" }
+                    },
+                    "swisstopo": {
+                        "url_template": "https://{s}.example.com/{layer}/{style}/{time}/{z}/{x}/{y}.{ext}",
+                        "subdomains": ["wmts0"],
+                        "variables": { "style": "default" }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let tile = TileId { z: 3, x: 3, y: 5 };
+        assert_eq!(
+            config
+                .url_template(TileSet::Osm, None)
+                .unwrap()
+                .render(tile, false, None),
+            "https://c.tiles.example.com/3/3/2.png?q=213&key=abc"
+        );
+        // The tileset still fills in its layers and times
+        let aerial = config.source_layer(TileSet::Swisstopo, "swissimage");
+        assert_eq!(
+            config
+                .url_template(TileSet::Swisstopo, aerial.as_ref())
+                .unwrap()
+                .render(tile, false, Some("2019")),
+            "https://wmts0.example.com/ch.swisstopo.swissimage/default/2019/3/3/5.jpeg"
+        );
+
+        for (json, expected) in [
+            (
+                r#"{ "tilesets": { "osm": { "url_template": "https://{s}.example.com/{z}/{x}/{y}.png" } } }"#,
+                "uses {s}, but there are no subdomains",
+            ),
+            (
+                r#"{ "tilesets": { "osm": { "url_template": "https://example.com/{z}/{x}/{y}{r}.png" } } }"#,
+                "osm doesn't serve retina tiles",
+            ),
+            (
+                r#"{ "tilesets": { "osm": { "url_template": "https://example.com/{z}/{x}/{y}.png?key={key}" } } }"#,
+                "Unknown variable {key}",
+            ),
+            (
+                r#"{ "tilesets": { "osm": { "subdomains": ["a", ""] } } }"#,
+                "empty subdomain for osm",
+            ),
+            (
+                r#"{ "tilesets": { "osm": { "variables": { "z": "1" } } } }"#,
+                "variable {z} of osm is already one of the URL template's",
+            ),
+            (
+                r#"{ "tilesets": { "swisstopo": { "variables": { "layer": "a" } } } }"#,
+                "variable {layer} of swisstopo is already one of the URL template's",
+            ),
+            (
+                r#"{ "tilesets": { "wms": { "url_template": "https://example.com/{z}/{x}/{y}.png" } } }"#,
+                "wms doesn't fetch its tiles from URLs",
+            ),
+        ] {
+            let err = Config::from_json(json).unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{err:#}");
+        }
+    }
+
+    #[test]
+    fn test_apply_query() {
+        let config = TileSetConfig {
+            user_agent: None,
+            headers: vec![],
+            query: vec![("api key".to_string(), Secret("a&b=c".to_string()))],
+            times: vec![],
+            layers: vec![],
+            url_template: None,
+            subdomains: vec![],
+            variables: vec![],
+            attribution: None,
+            wms: None,
+            mbtiles: None,
+            pmtiles: None,
+            directory: None,
+            min_zoom: None,
+            max_zoom: None,
+            coverage: None,
+        };
+
+        let (full, redacted) = config.apply_query("https://example.com/1/2/3.png");
+        assert_eq!(full, "https://example.com/1/2/3.png?api%20key=a%26b%3Dc");
+        assert_eq!(
+            redacted,
+            "https://example.com/1/2/3.png?api%20key=[redacted]"
+        );
+
+        let (full, _) = config.apply_query("https://example.com/tile?z=1");
+        assert_eq!(full, "https://example.com/tile?z=1&api%20key=a%26b%3Dc");
+    }
+}
@@ -7,17 +7,172 @@
 
 use log::debug;
 
+// The edge length of a standard slippy-map tile, in pixels. Some tilesets serve
+// 512px tiles, or "retina" tiles at twice their usual size.
+pub const TILE_SIZE_PX: u32 = 256;
+
+// The deepest zoom level we'll ever render at. Tilesets that stop short of this are
+// overzoomed: we fetch their deepest tiles and scale them up.
+pub const MAX_ZOOM: u32 = 21;
+
+// The most zoom levels we'll scale a tileset's deepest tiles up by. Past this the tiles are
+// mush anyway, and the radius is better off bigger.
+pub const MAX_OVERZOOM: u32 = 6;
+
+// The mean radius of the earth, which we treat as a sphere
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+// Half of the width of the webmercator (EPSG:3857) plane, in metres
+const WEB_MERCATOR_HALF_EXTENT_M: f64 = 20_037_508.342_789_244;
+
 // A latitude/longitude pair
 #[derive(Debug, Clone, Copy)]
 pub struct LatLong(pub f64, pub f64);
 
 // A tile coordinate. Note that a 'zoomLevel' value
-// must be carried along with this too
+// must be carried along with this too. At z=21 x and y run into the
+// millions, so we need f64 to keep any sub-tile precision at all.
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct TileCoordinate {
-    pub x: f32,
-    pub y: f32,
+    pub x: f64,
+    pub y: f64,
+    pub z: u32,
+}
+
+// The integer address of a single tile, as used by tile servers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TileId {
     pub z: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+// A position in the "world" pixel space at zoom z. The whole webmercator
+// plane is (tile_size * 2^z) pixels along each edge, with (0, 0) at the
+// top-left corner (180°W, ~85°N).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorldPixel {
+    pub x: f64,
+    pub y: f64,
+    pub z: u32,
+}
+
+// A position within a rendered image, relative to its top-left corner
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImagePixel {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl TileId {
+    // Whether the tile is on the map at all, i.e. its zoom level is one we support and x and y
+    // are within the 2^z tiles along each edge at that level
+    pub fn is_valid(self) -> bool {
+        self.z <= MAX_ZOOM && self.x < (1 << self.z) && self.y < (1 << self.z)
+    }
+}
+
+impl TileCoordinate {
+    pub fn to_world_pixel(self, tile_size: u32) -> WorldPixel {
+        WorldPixel {
+            x: self.x * tile_size as f64,
+            y: self.y * tile_size as f64,
+            z: self.z,
+        }
+    }
+
+    // The tile this coordinate falls within
+    pub fn tile_id(self) -> TileId {
+        TileId {
+            z: self.z,
+            x: self.x.floor() as u32,
+            y: self.y.floor() as u32,
+        }
+    }
+}
+
+impl TileId {
+    // The coordinate of the top-left corner of this tile
+    pub fn top_left(self) -> TileCoordinate {
+        TileCoordinate {
+            x: self.x as f64,
+            y: self.y as f64,
+            z: self.z,
+        }
+    }
+
+    // The [west, south, east, north] bounds of this tile, in decimal degrees
+    pub fn bbox_wgs84(self) -> [f64; 4] {
+        let LatLong(north, west) = tile_coords_to_lat_long(&self.top_left());
+        let LatLong(south, east) = tile_coords_to_lat_long(&TileCoordinate {
+            x: (self.x + 1) as f64,
+            y: (self.y + 1) as f64,
+            z: self.z,
+        });
+        [west, south, east, north]
+    }
+
+    // The [min x, min y, max x, max y] bounds of this tile, in EPSG:3857 metres
+    pub fn bbox_epsg3857(self) -> [f64; 4] {
+        let bottom_right = TileCoordinate {
+            x: (self.x + 1) as f64,
+            y: (self.y + 1) as f64,
+            z: self.z,
+        };
+        let (min_x, max_y) = self
+            .top_left()
+            .to_world_pixel(TILE_SIZE_PX)
+            .to_web_mercator(TILE_SIZE_PX);
+        let (max_x, min_y) = bottom_right
+            .to_world_pixel(TILE_SIZE_PX)
+            .to_web_mercator(TILE_SIZE_PX);
+        [min_x, min_y, max_x, max_y]
+    }
+}
+
+impl WorldPixel {
+    pub fn to_tile_coordinate(self, tile_size: u32) -> TileCoordinate {
+        TileCoordinate {
+            x: self.x / tile_size as f64,
+            y: self.y / tile_size as f64,
+            z: self.z,
+        }
+    }
+
+    // Converts to EPSG:3857 (x, y) metres. Note that y increases northwards in
+    // EPSG:3857, but southwards in pixel space.
+    pub fn to_web_mercator(self, tile_size: u32) -> (f64, f64) {
+        let resolution = web_mercator_metres_per_pixel(self.z, tile_size);
+        (
+            self.x * resolution - WEB_MERCATOR_HALF_EXTENT_M,
+            WEB_MERCATOR_HALF_EXTENT_M - self.y * resolution,
+        )
+    }
+
+    // Converts to a position within an image whose top-left corner sits at `origin`
+    pub fn to_image_pixel(self, origin: &WorldPixel) -> ImagePixel {
+        ImagePixel {
+            x: self.x - origin.x,
+            y: self.y - origin.y,
+        }
+    }
+}
+
+impl ImagePixel {
+    // Converts back to world pixels, given the world position of the image's top-left corner
+    pub fn to_world_pixel(self, origin: &WorldPixel) -> WorldPixel {
+        WorldPixel {
+            x: origin.x + self.x,
+            y: origin.y + self.y,
+            z: origin.z,
+        }
+    }
+}
+
+// The edge length of a single world pixel at the given zoom, in EPSG:3857 metres. Note that this
+// is only the true ground distance at the equator; elsewhere multiply by cos(latitude).
+pub fn web_mercator_metres_per_pixel(zoom: u32, tile_size: u32) -> f64 {
+    2.0 * WEB_MERCATOR_HALF_EXTENT_M / (tile_size as f64 * 2.0_f64.powi(zoom as i32))
 }
 
 // Converts a lat/long pair to tile coordinates at a particular zoom
@@ -30,22 +185,110 @@ pub fn lat_long_to_tile_coords(point: &LatLong, zoom: u32) -> TileCoordinate {
 
     debug!("Center coord at z={2}: {0}, {1}", x_tile, y_tile, zoom);
     TileCoordinate {
-        x: x_tile as f32,
-        y: y_tile as f32,
+        x: x_tile,
+        y: y_tile,
         z: zoom,
     }
 }
 
+// Converts tile coordinates back to a lat/long pair. This is the inverse of
+// lat_long_to_tile_coords, and works for fractional tile coordinates too.
+pub fn tile_coords_to_lat_long(coord: &TileCoordinate) -> LatLong {
+    let n = 2.0_f64.powi(coord.z as i32);
+    let long = coord.x / n * 360.0 - 180.0;
+    let lat_rad = (std::f64::consts::PI * (1.0 - 2.0 * coord.y / n))
+        .sinh()
+        .atan();
+
+    LatLong(lat_rad.to_degrees(), long)
+}
+
 // An extension of a TileBox that allows us to specify extra information to constrain it. The inner_size
 // is the number of pixels that are actually "used", and the center is the center the TileBox was taken around.
-// This is a bit of a funny type as it mixes coordinate systems; it would be better if we changed this so that
-// we have pixel offsets into the image here based on centering around the point we have created the ConstrainedTileBox
-// for.
+// Use image_origin to find where the "used" pixels start in world pixel space. tile_size_px is the edge
+// length of the tiles we'll mosaic, which all of the pixel measurements are based on. overzoom is
+// the number of zoom levels past the tiles we wanted to go; the inner image is scaled up by
+// 2^overzoom to make up for it.
 #[derive(Debug, Copy, Clone)]
 pub struct ConstrainedTileBox {
     pub center: LatLong,
     pub tile_box: TileBox,
     pub inner_size_px: (u32, u32),
+    pub tile_size_px: u32,
+    pub overzoom: u32,
+}
+
+impl ConstrainedTileBox {
+    // The world pixel at the top-left corner of the inner (cropped) image. We snap the
+    // center to a whole pixel so that the crop lines up with the pixels of the mosaic.
+    pub fn image_origin(&self) -> WorldPixel {
+        let center = lat_long_to_tile_coords(&self.center, self.tile_box.top_left.z)
+            .to_world_pixel(self.tile_size_px);
+        WorldPixel {
+            x: center.x.floor() - (self.inner_size_px.0 / 2) as f64,
+            y: center.y.floor() - (self.inner_size_px.1 / 2) as f64,
+            z: center.z,
+        }
+    }
+
+    // How much we scale the inner image up by to make up for overzooming
+    pub fn overzoom_factor(&self) -> u32 {
+        1 << self.overzoom
+    }
+
+    // The size of the image we hand back, after scaling up for any overzoom
+    pub fn output_size_px(&self) -> (u32, u32) {
+        let factor = self.overzoom_factor();
+        (self.inner_size_px.0 * factor, self.inner_size_px.1 * factor)
+    }
+
+    // The world pixel at the bottom-right corner of the inner (cropped) image
+    pub fn image_bottom_right(&self) -> WorldPixel {
+        ImagePixel {
+            x: self.inner_size_px.0 as f64,
+            y: self.inner_size_px.1 as f64,
+        }
+        .to_world_pixel(&self.image_origin())
+    }
+
+    // The [west, south, east, north] bounds of the image, in decimal degrees
+    pub fn bbox_wgs84(&self) -> [f64; 4] {
+        let LatLong(north, west) =
+            tile_coords_to_lat_long(&self.image_origin().to_tile_coordinate(self.tile_size_px));
+        let LatLong(south, east) = tile_coords_to_lat_long(
+            &self
+                .image_bottom_right()
+                .to_tile_coordinate(self.tile_size_px),
+        );
+        [west, south, east, north]
+    }
+
+    // Whether the pixel is within the output image. Its far edges are one past the last pixel.
+    pub fn contains_image_pixel(&self, pixel: ImagePixel) -> bool {
+        let (width, height) = self.output_size_px();
+        (0.0..width as f64).contains(&pixel.x) && (0.0..height as f64).contains(&pixel.y)
+    }
+
+    // Maps a pixel within the output image back to a point on the earth
+    pub fn image_pixel_to_lat_long(&self, pixel: ImagePixel) -> LatLong {
+        let factor = self.overzoom_factor() as f64;
+        let inner = ImagePixel {
+            x: pixel.x / factor,
+            y: pixel.y / factor,
+        };
+        let world = inner.to_world_pixel(&self.image_origin());
+        tile_coords_to_lat_long(&world.to_tile_coordinate(self.tile_size_px))
+    }
+
+    // The size of the mosaic of every tile in the box, in pixels
+    pub fn outer_size_px(&self) -> (u32, u32) {
+        let top_left = self.tile_box.top_left.tile_id();
+        let bottom_right = self.tile_box.bottom_right.tile_id();
+        (
+            (bottom_right.x - top_left.x + 1) * self.tile_size_px,
+            (bottom_right.y - top_left.y + 1) * self.tile_size_px,
+        )
+    }
 }
 
 // A box of tiles
@@ -56,35 +299,42 @@ pub struct TileBox {
 }
 
 impl TileBox {
-    pub fn _outer_size_px(&self) -> (u32, u32) {
-        (
-            (256.0 * (self.bottom_right.x - self.top_left.x)) as u32,
-            (256.0 * (self.bottom_right.y - self.top_left.y)) as u32,
-        )
+    pub fn outer_top_left(&self) -> TileId {
+        self.top_left.tile_id()
     }
 
-    pub fn outer_top_left(&self) -> (u32, u32) {
-        (
-            self.top_left.x.floor() as u32,
-            self.top_left.y.floor() as u32,
-        )
+    // All of the tiles needed to cover this box, row by row
+    pub fn tile_ids(&self) -> Vec<TileId> {
+        let top_left = self.top_left.tile_id();
+        let bottom_right = self.bottom_right.tile_id();
+
+        let mut ids = Vec::new();
+        for x in top_left.x..=bottom_right.x {
+            for y in top_left.y..=bottom_right.y {
+                ids.push(TileId {
+                    z: self.top_left.z,
+                    x,
+                    y,
+                });
+            }
+        }
+        ids
     }
 }
 
-// Given a point on the earth, a radius, and a desired zoom level, this function produces a
-// ConstrainedTileBox that contains enough pixels to cover the given area.
+// Given a point on the earth, a radius, a desired zoom level and the size of the tiles we'll
+// use, this function produces a ConstrainedTileBox that contains enough pixels to cover the given area.
 fn lat_long_and_radius_to_tile_box(
     point: &LatLong,
-    radius_km: f32,
+    radius_km: f64,
     zoom: u32,
+    tile_size: u32,
 ) -> ConstrainedTileBox {
-    let earth_radius_km = 6371.0;
-
     // Convert the center point to tile coordinates
     let center_tile = lat_long_to_tile_coords(point, zoom);
 
     // Calculate the approximate size of one tile in kilometers at the given zoom level
-    let tile_size_km = tile_size_kms(zoom, earth_radius_km);
+    let tile_size_km = tile_size_kms(zoom, EARTH_RADIUS_KM);
 
     // Calculate the number of tiles that fit into the radius (in both directions)
     let radius_tiles = radius_km / tile_size_km;
@@ -103,7 +353,7 @@ fn lat_long_and_radius_to_tile_box(
 
     // What's the inner resolution for our given radius? E.g., if we get zoom level '0' and ask
     // for a 10k radius, it's going to be very close to zero pixels
-    let inner_size_px = (256.0 * radius_tiles) as u32;
+    let inner_size_px = (tile_size as f64 * radius_tiles) as u32;
 
     // Print some helpful debugging info
     debug!(
@@ -114,6 +364,8 @@ fn lat_long_and_radius_to_tile_box(
     ConstrainedTileBox {
         center: *point,
         inner_size_px: (inner_size_px, inner_size_px),
+        tile_size_px: tile_size,
+        overzoom: 0,
         tile_box: TileBox {
             top_left: top_left_tile,
             bottom_right: bottom_right_tile,
@@ -123,37 +375,59 @@ fn lat_long_and_radius_to_tile_box(
 
 // tile_size_kms calculates the size of a tile at the given zoom level in kilometers.
 // in webmercator, the size of a tile is the same on both axes
-fn tile_size_kms(zoom: u32, earth_radius_km: f64) -> f32 {
+fn tile_size_kms(zoom: u32, earth_radius_km: f64) -> f64 {
     let n = 2.0_f64.powi(zoom as i32);
-    ((earth_radius_km * 2.0 * std::f64::consts::PI) / n) as f32
+    (earth_radius_km * 2.0 * std::f64::consts::PI) / n
+}
+
+// The shallowest zoom level with more than image_size_px pixels across the radius, however
+// deep that is. Every level doubles the pixels, so we can work it out rather than search for
+// it. The radius has to be positive.
+pub fn zoom_for_radius(radius_km: f64, image_size_px: u32, tile_size: u32) -> u32 {
+    let pixels_at_zoom = |z: u32| tile_size as f64 * radius_km / tile_size_kms(z, EARTH_RADIUS_KM);
+    let needed = image_size_px as f64;
+
+    let mut zoom = (needed / pixels_at_zoom(0)).log2().floor().max(0.0) as u32;
+    // Rounding can leave us a level either side of the one we want
+    if zoom > 0 && pixels_at_zoom(zoom - 1).floor() > needed {
+        zoom -= 1;
+    }
+    while pixels_at_zoom(zoom).floor() <= needed {
+        zoom += 1;
+    }
+    zoom
 }
 
 // Given a center point, a desired image size, and a radius in kilometers, produces
 // a ConstrainedTileBox that provides enough pixels to cover the given area, ensuring
 // we have (image_size_px / 2) pixels available to the left/right/above/below of the
 // center point. This also means we have to pick an appropriate zoom level to get
-// the resolution we need.
+// the resolution we need. Bigger tiles carry more pixels per zoom level, so for a given
+// image size they'll get us there at a lower zoom. If that zoom is past max_zoom, we take
+// the tiles at max_zoom instead and mark the box as overzoomed, by at most MAX_OVERZOOM
+// levels; callers should turn away radii that need more.
 pub fn lat_long_and_image_size_to_bounding_box(
     center: LatLong,
-    radius_km: f32,
+    radius_km: f64,
     image_size_px: u32,
+    tile_size: u32,
+    max_zoom: u32,
 ) -> ConstrainedTileBox {
-    // Generate a list of zoom levels from 0 to 21
-    let zooms: Vec<u32> = (0..=21).collect();
-    let candidates: Vec<(u32, ConstrainedTileBox)> = zooms
-        .iter()
-        .map(|z| (*z, lat_long_and_radius_to_tile_box(&center, radius_km, *z)))
-        .filter(|c| c.1.inner_size_px.0 > image_size_px)
-        .take(1) // stop once we find one
-        .collect();
-
-    let best_candidate = candidates.first().expect("We shoulda found one");
+    let zoom = zoom_for_radius(radius_km, image_size_px, tile_size);
+    debug!("Zoom {0} has enough pixels for the radius", zoom);
+    if zoom <= max_zoom {
+        return lat_long_and_radius_to_tile_box(&center, radius_km, zoom, tile_size);
+    }
+
+    // The tileset doesn't go deep enough, so use its deepest tiles and scale them up
     debug!(
-        "best_candidate: {0}, {1:?}",
-        best_candidate.0, best_candidate.1
+        "Zoom {0} is past the tileset's max zoom of {1}, overzooming",
+        zoom, max_zoom
     );
-
-    best_candidate.1
+    ConstrainedTileBox {
+        overzoom: (zoom - max_zoom).min(MAX_OVERZOOM),
+        ..lat_long_and_radius_to_tile_box(&center, radius_km, max_zoom, tile_size)
+    }
 }
 
 #[cfg(test)]
@@ -162,9 +436,9 @@ mod tests {
     use super::*;
     use float_cmp::*;
 
-    const MARGIN: F32Margin = F32Margin {
+    const MARGIN: F64Margin = F64Margin {
         ulps: 2,
-        epsilon: 0.0,
+        epsilon: 1e-9,
     };
 
     #[test]
@@ -173,8 +447,8 @@ mod tests {
 
         debug!("{0}, {1}", x, y);
 
-        assert!(x.approx_eq(0.819_444_4, MARGIN));
-        assert!(y.approx_eq(0.590_648_7, MARGIN));
+        assert!(x.approx_eq(0.819_444_444_444, MARGIN));
+        assert!(y.approx_eq(0.590_648_724_413, MARGIN));
     }
 
     #[test]
@@ -184,8 +458,8 @@ mod tests {
         let zoom = 12;
         let TileCoordinate { x, y, z } = lat_long_to_tile_coords(&LatLong(lat, lon), zoom);
 
-        assert!(x.approx_eq(3_366.248_8, MARGIN));
-        assert!(y.approx_eq(2_431.989_7, MARGIN));
+        assert!(x.approx_eq(3_366.248_675_555_556, MARGIN));
+        assert!(y.approx_eq(2_431.989_778_858_459, MARGIN));
         assert_eq!(z, zoom);
     }
 
@@ -195,11 +469,263 @@ mod tests {
         let lon = 7.6280;
         let zoom = 14;
         let TileCoordinate { x, y, z } = lat_long_to_tile_coords(&LatLong(lat, lon), zoom);
-        assert!(x.approx_eq(8539.159, MARGIN));
-        assert!(y.approx_eq(5778.795, MARGIN));
+        assert!(x.approx_eq(8_539.158_755_555_554, MARGIN));
+        assert!(y.approx_eq(5_778.795_171_651_929, MARGIN));
         assert_eq!(z, zoom);
     }
 
+    #[test]
+    fn test_lat_long_to_tile_coords_keeps_precision_at_z21() {
+        // At z=21 an f32 would only resolve to ~0.25 tile; we should be able to
+        // tell apart points that are a couple of centimetres apart.
+        let a = lat_long_to_tile_coords(&LatLong(46.655559, 8.102121), 21);
+        let b = lat_long_to_tile_coords(&LatLong(46.655559, 8.1021212), 21);
+
+        assert!(b.x > a.x);
+        assert!((b.x - a.x) < 0.01);
+    }
+
+    #[test]
+    fn test_world_and_image_pixel_round_trip() {
+        let coord = TileCoordinate {
+            x: 3_366.25,
+            y: 2_431.5,
+            z: 12,
+        };
+        let world = coord.to_world_pixel(TILE_SIZE_PX);
+        assert_eq!(world.x, 861_760.0);
+        assert_eq!(world.y, 622_464.0);
+
+        let origin = WorldPixel {
+            x: 861_700.0,
+            y: 622_400.0,
+            z: 12,
+        };
+        let image = world.to_image_pixel(&origin);
+        assert_eq!(image, ImagePixel { x: 60.0, y: 64.0 });
+
+        assert_eq!(image.to_world_pixel(&origin), world);
+        assert_eq!(world.to_tile_coordinate(TILE_SIZE_PX), coord);
+    }
+
+    #[test]
+    fn test_tile_coords_to_lat_long_round_trip() {
+        let point = LatLong(-31.9514, 115.8617);
+        let coord = lat_long_to_tile_coords(&point, 12);
+
+        let LatLong(lat, long) = tile_coords_to_lat_long(&coord);
+        assert!(lat.approx_eq(point.0, MARGIN));
+        assert!(long.approx_eq(point.1, MARGIN));
+
+        // The top-left corner of the world
+        let LatLong(lat, long) = tile_coords_to_lat_long(&TileCoordinate {
+            x: 0.0,
+            y: 0.0,
+            z: 0,
+        });
+        assert!(lat.approx_eq(85.051_128_779_806_6, MARGIN));
+        assert!(long.approx_eq(-180.0, MARGIN));
+    }
+
+    #[test]
+    fn test_image_pixel_to_lat_long() {
+        let center = LatLong(46.655559, 8.102121);
+        let tile_box =
+            lat_long_and_image_size_to_bounding_box(center, 3.0, 1024, TILE_SIZE_PX, MAX_ZOOM);
+
+        // The middle of the image should land (to within a pixel) on the center
+        let middle = ImagePixel {
+            x: (tile_box.inner_size_px.0 / 2) as f64,
+            y: (tile_box.inner_size_px.1 / 2) as f64,
+        };
+        let LatLong(lat, long) = tile_box.image_pixel_to_lat_long(middle);
+        assert!((lat - center.0).abs() < 1e-4);
+        assert!((long - center.1).abs() < 1e-4);
+
+        // Moving right and down should move east and south
+        let LatLong(lat_br, long_br) = tile_box.image_pixel_to_lat_long(ImagePixel {
+            x: tile_box.inner_size_px.0 as f64,
+            y: tile_box.inner_size_px.1 as f64,
+        });
+        assert!(lat_br < lat);
+        assert!(long_br > long);
+    }
+
+    #[test]
+    fn test_contains_image_pixel() {
+        let center = LatLong(46.655559, 8.102121);
+        let tile_box =
+            lat_long_and_image_size_to_bounding_box(center, 3.0, 1024, TILE_SIZE_PX, MAX_ZOOM);
+        let (width, height) = tile_box.output_size_px();
+        let (width, height) = (width as f64, height as f64);
+
+        for (x, y) in [(0.0, 0.0), (width - 0.5, height - 0.5), (width - 1.0, 0.0)] {
+            assert!(tile_box.contains_image_pixel(ImagePixel { x, y }), "{x} {y}");
+        }
+        // The far edges are just past the image
+        for (x, y) in [(width, 0.0), (0.0, height), (width, height), (-0.5, 0.0)] {
+            assert!(!tile_box.contains_image_pixel(ImagePixel { x, y }), "{x} {y}");
+        }
+    }
+
+    #[test]
+    fn test_world_pixel_to_web_mercator() {
+        // The whole world fits into a single tile at z=0
+        let top_left = WorldPixel {
+            x: 0.0,
+            y: 0.0,
+            z: 0,
+        };
+        let (x, y) = top_left.to_web_mercator(TILE_SIZE_PX);
+        assert!(x.approx_eq(-WEB_MERCATOR_HALF_EXTENT_M, MARGIN));
+        assert!(y.approx_eq(WEB_MERCATOR_HALF_EXTENT_M, MARGIN));
+
+        let middle = WorldPixel {
+            x: 512.0,
+            y: 512.0,
+            z: 2,
+        };
+        let (x, y) = middle.to_web_mercator(TILE_SIZE_PX);
+        assert!(x.approx_eq(0.0, MARGIN));
+        assert!(y.approx_eq(0.0, MARGIN));
+
+        assert!(
+            web_mercator_metres_per_pixel(0, TILE_SIZE_PX).approx_eq(156_543.033_928_041, MARGIN)
+        );
+    }
+
+    #[test]
+    fn test_bigger_tiles_need_lower_zoom() {
+        let center = LatLong(46.655559, 8.102121);
+        let small =
+            lat_long_and_image_size_to_bounding_box(center, 3.0, 1024, TILE_SIZE_PX, MAX_ZOOM);
+        let big =
+            lat_long_and_image_size_to_bounding_box(center, 3.0, 1024, 2 * TILE_SIZE_PX, MAX_ZOOM);
+
+        // 512px tiles at z carry the same pixels as 256px tiles at z+1
+        assert_eq!(big.tile_box.top_left.z + 1, small.tile_box.top_left.z);
+        assert_eq!(big.inner_size_px, small.inner_size_px);
+        assert_eq!(big.image_origin().x, small.image_origin().x);
+    }
+
+    #[test]
+    fn test_overzoom_past_max_zoom() {
+        let center = LatLong(46.655559, 8.102121);
+        let deep =
+            lat_long_and_image_size_to_bounding_box(center, 0.1, 1024, TILE_SIZE_PX, MAX_ZOOM);
+        let capped = lat_long_and_image_size_to_bounding_box(center, 0.1, 1024, TILE_SIZE_PX, 16);
+        assert_eq!(deep.overzoom, 0);
+
+        // We stop at the max zoom, and scale back up to (about) the same image size
+        assert_eq!(capped.tile_box.top_left.z, 16);
+        assert_eq!(capped.overzoom, deep.tile_box.top_left.z - 16);
+        assert!(capped.output_size_px().0 > 1024);
+        assert!(
+            capped.output_size_px().0.abs_diff(deep.inner_size_px.0) <= capped.overzoom_factor()
+        );
+
+        // Pixels in the scaled-up image should land in the same place as in the deep one, give
+        // or take the pixel we snap the center to at z16 (about 2e-5 degrees)
+        let pixel = ImagePixel { x: 100.0, y: 900.0 };
+        let LatLong(lat, long) = capped.image_pixel_to_lat_long(pixel);
+        let LatLong(deep_lat, deep_long) = deep.image_pixel_to_lat_long(pixel);
+        assert!((lat - deep_lat).abs() < 5e-5);
+        assert!((long - deep_long).abs() < 5e-5);
+    }
+
+    #[test]
+    fn test_zoom_for_radius() {
+        // Each zoom is the first with enough pixels, which is what searching them all finds
+        for (radius_km, size_px) in [(1.0, 512), (3.0, 1024), (0.1, 1024), (500.0, 256)] {
+            let zoom = zoom_for_radius(radius_km, size_px, TILE_SIZE_PX);
+            let center = LatLong(46.655559, 8.102121);
+            let inner = |z| lat_long_and_radius_to_tile_box(&center, radius_km, z, TILE_SIZE_PX);
+            assert!(inner(zoom).inner_size_px.0 > size_px);
+            if zoom > 0 {
+                assert!(inner(zoom - 1).inner_size_px.0 <= size_px);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tiny_radius_past_max_zoom() {
+        // A 10cm radius over 4096px needs tiles far deeper than anyone serves
+        let center = LatLong(46.655559, 8.102121);
+        let zoom = zoom_for_radius(0.0001, 4096, TILE_SIZE_PX);
+        assert!(zoom > MAX_ZOOM + MAX_OVERZOOM);
+
+        let tile_box =
+            lat_long_and_image_size_to_bounding_box(center, 0.0001, 4096, TILE_SIZE_PX, MAX_ZOOM);
+        assert_eq!(tile_box.tile_box.top_left.z, MAX_ZOOM);
+        assert_eq!(tile_box.overzoom, MAX_OVERZOOM);
+
+        // Within the limit, we scale back up to the size we were asked for
+        let radius_km = 0.005;
+        let zoom = zoom_for_radius(radius_km, 4096, TILE_SIZE_PX);
+        assert!(zoom > MAX_ZOOM && zoom <= MAX_ZOOM + MAX_OVERZOOM);
+        let tile_box = lat_long_and_image_size_to_bounding_box(
+            center,
+            radius_km,
+            4096,
+            TILE_SIZE_PX,
+            MAX_ZOOM,
+        );
+        assert_eq!(tile_box.overzoom, zoom - MAX_ZOOM);
+        assert!(tile_box.output_size_px().0 > 4096);
+    }
+
+    #[test]
+    fn test_tile_box_tile_ids() {
+        let tile_box = TileBox {
+            top_left: TileCoordinate {
+                x: 10.2,
+                y: 20.9,
+                z: 5,
+            },
+            bottom_right: TileCoordinate {
+                x: 11.7,
+                y: 21.1,
+                z: 5,
+            },
+        };
+
+        let ids = tile_box.tile_ids();
+        assert_eq!(ids.len(), 4);
+        assert!(ids.contains(&TileId { z: 5, x: 10, y: 20 }));
+        assert!(ids.contains(&TileId { z: 5, x: 11, y: 21 }));
+        assert_eq!(tile_box.outer_top_left(), TileId { z: 5, x: 10, y: 20 });
+    }
+
+    #[test]
+    fn test_tile_id_bbox_wgs84() {
+        // At z=1 the world splits into four, so the top-left tile is the north-west quarter
+        let [west, south, east, north] = TileId { z: 1, x: 0, y: 0 }.bbox_wgs84();
+        assert!(west.approx_eq(-180.0, MARGIN));
+        assert!(south.approx_eq(0.0, MARGIN));
+        assert!(east.approx_eq(0.0, MARGIN));
+        assert!(north.approx_eq(85.051_128_779_806_59, MARGIN));
+
+        let [min_x, min_y, max_x, max_y] = TileId { z: 1, x: 0, y: 0 }.bbox_epsg3857();
+        assert!(min_x.approx_eq(-WEB_MERCATOR_HALF_EXTENT_M, MARGIN));
+        assert!(min_y.approx_eq(0.0, MARGIN));
+        assert!(max_x.approx_eq(0.0, MARGIN));
+        assert!(max_y.approx_eq(WEB_MERCATOR_HALF_EXTENT_M, MARGIN));
+    }
+
+    #[test]
+    fn test_tile_id_is_valid() {
+        assert!(TileId { z: 0, x: 0, y: 0 }.is_valid());
+        assert!(TileId { z: 3, x: 7, y: 7 }.is_valid());
+        assert!(!TileId { z: 3, x: 8, y: 0 }.is_valid());
+        assert!(!TileId { z: 3, x: 0, y: 8 }.is_valid());
+        assert!(!TileId {
+            z: MAX_ZOOM + 1,
+            x: 0,
+            y: 0
+        }
+        .is_valid());
+    }
+
     #[test]
     fn test_lat_long_and_radius_to_tile_box_perth() {
         let lat = -31.9514;
@@ -215,7 +741,7 @@ mod tests {
                     bottom_right,
                 },
             ..
-        } = lat_long_and_radius_to_tile_box(&LatLong(lat, lon), radius_km, zoom);
+        } = lat_long_and_radius_to_tile_box(&LatLong(lat, lon), radius_km, zoom, TILE_SIZE_PX);
 
         // Assertions - rough values, need fixing with exact ones
         let TileCoordinate {
@@ -229,10 +755,10 @@ mod tests {
             z: _bottom_right_z,
         } = bottom_right;
 
-        assert!(top_left_x.approx_eq(3_366.044_2, MARGIN));
-        assert!(top_left_y.approx_eq(2_431.785_2, MARGIN));
-        assert!(bottom_right_x.approx_eq(3_366.453_4, MARGIN));
-        assert!(bottom_right_y.approx_eq(2_432.194_3, MARGIN));
+        assert!(top_left_x.approx_eq(3_366.044_029_927_898, MARGIN));
+        assert!(top_left_y.approx_eq(2_431.785_133_230_801, MARGIN));
+        assert!(bottom_right_x.approx_eq(3_366.453_321_183_214, MARGIN));
+        assert!(bottom_right_y.approx_eq(2_432.194_424_486_117, MARGIN));
     }
 
     #[test]
@@ -250,7 +776,13 @@ mod tests {
                     bottom_right,
                 },
             ..
-        } = lat_long_and_image_size_to_bounding_box(LatLong(lat, lon), radius_km, image_size_px);
+        } = lat_long_and_image_size_to_bounding_box(
+            LatLong(lat, lon),
+            radius_km,
+            image_size_px,
+            TILE_SIZE_PX,
+            MAX_ZOOM,
+        );
 
         // Rough assertions for the zoom and tile coordinates
         // assert_eq!(zoom, 14); // Adjust this value based on actual results
@@ -266,9 +798,9 @@ mod tests {
             z: _bottom_right_z,
         } = bottom_right;
 
-        assert!(top_left_x.approx_eq(13_460.902, MARGIN));
-        assert!(top_left_y.approx_eq(9_723.866, MARGIN));
-        assert!(bottom_right_x.approx_eq(13_469.088, MARGIN));
-        assert!(bottom_right_y.approx_eq(9_732.052, MARGIN));
+        assert!(top_left_x.approx_eq(13_460.901_789_669_064, MARGIN));
+        assert!(top_left_y.approx_eq(9_723.866_202_880_676, MARGIN));
+        assert!(bottom_right_x.approx_eq(13_469.087_614_775_38, MARGIN));
+        assert!(bottom_right_y.approx_eq(9_732.052_027_986_992, MARGIN));
     }
 }
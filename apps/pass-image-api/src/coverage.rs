@@ -0,0 +1,125 @@
+// ! # coverage
+// !
+// ! Describes the part of the world a tileset has imagery for, so that we can skip
+// ! tiles (or whole requests) that it can't serve.
+// !
+
+use serde::Serialize;
+
+// Web mercator stops short of the poles, at the latitude that makes the world square
+pub const WEB_MERCATOR_MAX_LAT: f64 = 85.051_128_779_806_59;
+
+// Everywhere a web mercator tileset can have tiles for
+pub const WEB_MERCATOR_BBOX: [f64; 4] =
+    [-180.0, -WEB_MERCATOR_MAX_LAT, 180.0, WEB_MERCATOR_MAX_LAT];
+
+// The area a tileset covers. Bounding boxes are [west, south, east, north] in decimal degrees,
+// and polygons are a ring of [long, lat] points like GeoJSON. The ring doesn't need to be
+// closed; we join the last point back to the first.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Coverage {
+    Bbox([f64; 4]),
+    Polygon(Vec<[f64; 2]>),
+}
+
+impl Coverage {
+    // Whether any part of the [west, south, east, north] bbox falls within the coverage
+    pub fn intersects(&self, bbox: [f64; 4]) -> bool {
+        match self {
+            Coverage::Bbox(coverage) => bboxes_overlap(*coverage, bbox),
+            Coverage::Polygon(ring) => polygon_intersects_bbox(ring, bbox),
+        }
+    }
+}
+
+fn bboxes_overlap(a: [f64; 4], b: [f64; 4]) -> bool {
+    let [a_west, a_south, a_east, a_north] = a;
+    let [b_west, b_south, b_east, b_north] = b;
+    a_west < b_east && a_east > b_west && a_south < b_north && a_north > b_south
+}
+
+// The polygon and the box intersect if a corner of one is inside the other, or failing
+// that, if any of their edges cross
+fn polygon_intersects_bbox(ring: &[[f64; 2]], bbox: [f64; 4]) -> bool {
+    let [west, south, east, north] = bbox;
+    let corners = [[west, south], [east, south], [east, north], [west, north]];
+
+    if ring
+        .iter()
+        .any(|&[x, y]| west <= x && x <= east && south <= y && y <= north)
+    {
+        return true;
+    }
+    if corners.iter().any(|corner| ring_contains(ring, *corner)) {
+        return true;
+    }
+
+    edges(ring)
+        .any(|ring_edge| edges(&corners).any(|bbox_edge| segments_cross(ring_edge, bbox_edge)))
+}
+
+// Even-odd ray casting: count the edges a ray heading east from the point crosses
+fn ring_contains(ring: &[[f64; 2]], point: [f64; 2]) -> bool {
+    let [x, y] = point;
+    edges(ring)
+        .filter(|&([x1, y1], [x2, y2])| {
+            (y1 > y) != (y2 > y) && x < x1 + (y - y1) * (x2 - x1) / (y2 - y1)
+        })
+        .count()
+        % 2
+        == 1
+}
+
+// Each edge of the ring, including the one joining the last point back to the first
+fn edges(ring: &[[f64; 2]]) -> impl Iterator<Item = ([f64; 2], [f64; 2])> + '_ {
+    ring.iter()
+        .zip(ring.iter().cycle().skip(1))
+        .map(|(a, b)| (*a, *b))
+}
+
+fn segments_cross(a: ([f64; 2], [f64; 2]), b: ([f64; 2], [f64; 2])) -> bool {
+    // Which side of the line through p and q the point r falls on
+    fn side(p: [f64; 2], q: [f64; 2], r: [f64; 2]) -> f64 {
+        (q[0] - p[0]) * (r[1] - p[1]) - (q[1] - p[1]) * (r[0] - p[0])
+    }
+
+    let (a1, a2) = a;
+    let (b1, b2) = b;
+    side(a1, a2, b1) * side(a1, a2, b2) < 0.0 && side(b1, b2, a1) * side(b1, b2, a2) < 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A diamond centered on (0, 0)
+    fn diamond() -> Coverage {
+        Coverage::Polygon(vec![[0.0, 10.0], [10.0, 0.0], [0.0, -10.0], [-10.0, 0.0]])
+    }
+
+    #[test]
+    fn test_bbox_coverage() {
+        let coverage = Coverage::Bbox([5.0, 45.0, 11.0, 48.0]);
+        assert!(coverage.intersects([7.0, 46.0, 7.5, 46.5]));
+        assert!(coverage.intersects([0.0, 40.0, 6.0, 46.0]));
+        assert!(!coverage.intersects([115.0, -32.0, 116.0, -31.0]));
+        assert!(Coverage::Bbox(WEB_MERCATOR_BBOX).intersects([115.0, -32.0, 116.0, -31.0]));
+    }
+
+    #[test]
+    fn test_polygon_coverage() {
+        let coverage = diamond();
+
+        // Entirely inside, entirely containing, and overlapping a corner
+        assert!(coverage.intersects([-1.0, -1.0, 1.0, 1.0]));
+        assert!(coverage.intersects([-20.0, -20.0, 20.0, 20.0]));
+        assert!(coverage.intersects([8.0, -1.0, 12.0, 1.0]));
+
+        // Inside the polygon's bbox, but outside the polygon itself
+        assert!(!coverage.intersects([7.0, 7.0, 9.0, 9.0]));
+
+        // A thin box straddling the polygon without either having a corner inside the other
+        assert!(coverage.intersects([4.0, -20.0, 5.0, 20.0]));
+    }
+}
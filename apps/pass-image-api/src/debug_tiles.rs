@@ -0,0 +1,198 @@
+// ! # debug_tiles
+// !
+// ! Draws tiles on the fly for the debug tileset: a checkerboard or gradient with a border
+// ! around the tile and its z/x/y written in the middle. Laid over a real map, or rendered
+// ! on their own, they show exactly which tiles an image was made from and where they landed.
+// !
+
+use crate::coordinates::{TileId, MAX_ZOOM};
+use crate::tiles::{Tile, TileFormat};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use image::{ImageFormat, Rgba, RgbaImage};
+use std::io::Cursor;
+
+// The squares along each side of a checkerboard tile. Even, so that the pattern carries on
+// unbroken from one tile to the next.
+const CHECKERBOARD_SQUARES: u32 = 8;
+
+const BORDER: Rgba<u8> = Rgba([200, 0, 0, 255]);
+const TEXT: Rgba<u8> = Rgba([0, 0, 0, 255]);
+const TEXT_BACKGROUND: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+// A 3x5 pixel font for the characters in "z/x/y", one row per byte with the leftmost pixel
+// in the highest of the three bits
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+const SLASH: [u8; 5] = [0b001, 0b001, 0b010, 0b100, 0b100];
+
+// What to fill the tiles with, picked as the debug tileset's layer
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Pattern {
+    Checkerboard,
+    // Red across the tile, green down it, and blue deepening with the zoom level
+    Gradient,
+}
+
+impl Pattern {
+    pub fn from_name(name: &str) -> Option<Pattern> {
+        match name {
+            "checkerboard" => Some(Pattern::Checkerboard),
+            "gradient" => Some(Pattern::Gradient),
+            _ => None,
+        }
+    }
+
+    fn colour(&self, tile: TileId, x: u32, y: u32, size_px: u32) -> Rgba<u8> {
+        match self {
+            Pattern::Checkerboard => {
+                let square = size_px / CHECKERBOARD_SQUARES;
+                match (x / square + y / square) % 2 {
+                    0 => Rgba([230, 230, 230, 255]),
+                    _ => Rgba([170, 170, 170, 255]),
+                }
+            }
+            Pattern::Gradient => Rgba([
+                (x * 255 / size_px) as u8,
+                (y * 255 / size_px) as u8,
+                (tile.z * 255 / MAX_ZOOM) as u8,
+                255,
+            ]),
+        }
+    }
+}
+
+// Draws the tile as a size_px square PNG
+pub fn render_tile(tile: TileId, size_px: u32, pattern: Pattern) -> Result<Tile> {
+    let mut image =
+        RgbaImage::from_fn(size_px, size_px, |x, y| pattern.colour(tile, x, y, size_px));
+
+    // Tiles sit edge to edge, so each one's border is only half as wide as the line between
+    // two of them
+    let border = (size_px / 256).max(1);
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        if x < border || y < border || x >= size_px - border || y >= size_px - border {
+            *pixel = BORDER;
+        }
+    }
+
+    draw_label(&mut image, &format!("{}/{}/{}", tile.z, tile.x, tile.y));
+
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| anyhow!("Failed to encode debug tile {:?}: {}", tile, e))?;
+    Ok(Tile {
+        format: TileFormat::Png,
+        bytes: Bytes::from(png),
+    })
+}
+
+// Writes the text in the middle of the image on a plain background, as large as will fit
+// across most of it
+fn draw_label(image: &mut RgbaImage, text: &str) {
+    let size_px = image.width();
+    let chars = text.len() as u32;
+    // Each character takes its glyph plus a pixel of space, and the background a pixel more
+    // all round
+    let text_width = chars * (GLYPH_WIDTH + 1) + 1;
+    let text_height = GLYPH_HEIGHT + 2;
+    let scale = (size_px * 3 / 4 / text_width).clamp(1, size_px / 64 + 1);
+
+    let left = (size_px - text_width * scale) / 2;
+    let top = (size_px - text_height * scale) / 2;
+    for y in 0..text_height * scale {
+        for x in 0..text_width * scale {
+            image.put_pixel(left + x, top + y, TEXT_BACKGROUND);
+        }
+    }
+
+    for (i, c) in text.chars().enumerate() {
+        let glyph = match c {
+            '0'..='9' => DIGITS[c as usize - '0' as usize],
+            _ => SLASH,
+        };
+        let glyph_left = left + (1 + i as u32 * (GLYPH_WIDTH + 1)) * scale;
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - column)) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        image.put_pixel(
+                            glyph_left + column * scale + dx,
+                            top + (1 + row as u32) * scale + dy,
+                            TEXT,
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_debug_tile() {
+        let tile = TileId {
+            z: 12,
+            x: 2132,
+            y: 1449,
+        };
+        for size_px in [256, 512] {
+            let rendered = render_tile(tile, size_px, Pattern::Checkerboard).unwrap();
+            let image = image::load_from_memory(&rendered.bytes).unwrap().to_rgba8();
+            assert_eq!(image.dimensions(), (size_px, size_px));
+
+            // A border all the way round, a checkerboard inside it, and the label in the middle
+            assert_eq!(*image.get_pixel(0, 100), BORDER);
+            assert_eq!(*image.get_pixel(size_px - 1, 100), BORDER);
+            assert_eq!(*image.get_pixel(100, size_px - 1), BORDER);
+            assert_ne!(
+                image.get_pixel(size_px / 16, size_px / 16),
+                image.get_pixel(size_px * 3 / 16, size_px / 16)
+            );
+            let middle =
+                (size_px / 2 - 20..size_px / 2 + 20).map(|x| *image.get_pixel(x, size_px / 2));
+            assert!(middle.clone().any(|p| p == TEXT));
+            assert!(middle.clone().any(|p| p == TEXT_BACKGROUND));
+        }
+
+        // The deepest tiles have the longest labels, which still have to fit
+        let deepest = (1 << MAX_ZOOM) - 1;
+        let tile = TileId {
+            z: MAX_ZOOM,
+            x: deepest,
+            y: deepest,
+        };
+        let rendered = render_tile(tile, 256, Pattern::Gradient).unwrap();
+        let image = image::load_from_memory(&rendered.bytes).unwrap().to_rgba8();
+        assert_eq!(image.get_pixel(20, 20)[2], 255);
+    }
+
+    #[test]
+    fn test_pattern_names() {
+        assert_eq!(
+            Pattern::from_name("checkerboard"),
+            Some(Pattern::Checkerboard)
+        );
+        assert_eq!(Pattern::from_name("gradient"), Some(Pattern::Gradient));
+        assert_eq!(Pattern::from_name("stripes"), None);
+    }
+}
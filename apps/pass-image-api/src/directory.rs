@@ -0,0 +1,148 @@
+// ! # directory
+// !
+// ! Reads tiles from a directory laid out like a tile server's URLs, {root}/{z}/{x}/{y}.{ext},
+// ! as written by most tile downloaders and seeders. It's the simplest source we can run
+// ! without a network.
+// !
+
+use crate::coordinates::{TileId, MAX_ZOOM};
+use crate::tiles::{Tile, TileFormat};
+use actix_web::web;
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use serde::Deserialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+// A directory of tiles, as configured in TILESETS_CONFIG
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DirectorySource {
+    root: PathBuf,
+    // The extension on the tile files, which also names their format
+    #[serde(default = "default_ext")]
+    ext: String,
+}
+
+fn default_ext() -> String {
+    "png".to_string()
+}
+
+impl DirectorySource {
+    // Checks that the root is a directory, and that we can decode tiles with the extension
+    pub fn validate(&self) -> Result<()> {
+        self.tile_format()?;
+        if !self.root.is_dir() {
+            return Err(anyhow!("{} isn't a directory", self.root.display()));
+        }
+        Ok(())
+    }
+
+    fn tile_format(&self) -> Result<TileFormat> {
+        TileFormat::from_name(&self.ext).ok_or_else(|| {
+            anyhow!(
+                "Unsupported tile extension '{}', expected png, jpeg, jpg or webp",
+                self.ext
+            )
+        })
+    }
+
+    // The zoom levels there are tiles for, going by the numbered directories under the root.
+    // Numbers past the deepest zoom we can ask for aren't zoom levels of ours.
+    pub fn zoom_levels(&self) -> Result<Vec<u32>> {
+        let mut zooms = Vec::new();
+        for entry in
+            fs::read_dir(&self.root).with_context(|| format!("listing {}", self.root.display()))?
+        {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let zoom = entry.file_name().to_str().and_then(|n| n.parse().ok());
+            if let Some(z) = zoom.filter(|z| *z <= MAX_ZOOM) {
+                zooms.push(z);
+            }
+        }
+        zooms.sort();
+        Ok(zooms)
+    }
+
+    // Reads a tile, or None if there's no file for it. The read waits on the disk, so it runs
+    // on actix's blocking thread pool rather than holding up the worker serving requests.
+    pub async fn read_tile(&self, tile: TileId) -> Result<Option<Tile>> {
+        let path = self
+            .root
+            .join(tile.z.to_string())
+            .join(tile.x.to_string())
+            .join(format!("{}.{}", tile.y, self.ext));
+
+        let read = web::block({
+            let path = path.clone();
+            move || fs::read(path)
+        })
+        .await
+        .map_err(|e| anyhow!("reading {}: {}", path.display(), e))?;
+        let bytes = match read {
+            Ok(bytes) => Bytes::from(bytes),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        // Trust what's in the file over what it's called
+        let format = match TileFormat::detect("", &bytes) {
+            Some(format) => format,
+            None => self.tile_format()?,
+        };
+
+        Ok(Some(Tile { format, bytes }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, Rgba, RgbaImage};
+    use std::io::Cursor;
+
+    #[tokio::test]
+    async fn test_read_directory_tiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("3/4")).unwrap();
+        fs::create_dir_all(root.join("5")).unwrap();
+        fs::create_dir_all(root.join("not-a-zoom")).unwrap();
+        fs::create_dir_all(root.join((MAX_ZOOM + 1).to_string())).unwrap();
+
+        let mut png = Vec::new();
+        RgbaImage::from_pixel(256, 256, Rgba([0, 0, 255, 255]))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        fs::write(root.join("3/4/2.png"), &png).unwrap();
+
+        let source: DirectorySource =
+            serde_json::from_value(serde_json::json!({ "root": root })).unwrap();
+        source.validate().unwrap();
+        assert_eq!(source.zoom_levels().unwrap(), vec![3, 5]);
+
+        let tile = source
+            .read_tile(TileId { z: 3, x: 4, y: 2 })
+            .await
+            .unwrap()
+            .expect("The tile is there");
+        assert_eq!(tile.format, TileFormat::Png);
+        assert_eq!(tile.bytes, png);
+        assert!(source
+            .read_tile(TileId { z: 3, x: 4, y: 3 })
+            .await
+            .unwrap()
+            .is_none());
+
+        for json in [
+            serde_json::json!({ "root": root, "ext": "gif" }),
+            serde_json::json!({ "root": root.join("nope") }),
+        ] {
+            let source: DirectorySource = serde_json::from_value(json.clone()).unwrap();
+            assert!(source.validate().is_err(), "{json}");
+        }
+    }
+}
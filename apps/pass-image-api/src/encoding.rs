@@ -0,0 +1,252 @@
+// ! # encoding
+// !
+// ! Turns a rendered image into the bytes we hand back to the caller, in whichever
+// ! output format they asked for.
+// !
+
+use crate::georef::ImageMetadata;
+use anyhow::{Context, Result};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{DynamicImage, RgbaImage};
+use std::io::Cursor;
+use tiff::encoder::{colortype, compression::Deflate, TiffEncoder};
+use tiff::tags::Tag;
+
+// The quality used for lossy formats when the caller doesn't ask for one
+pub const DEFAULT_QUALITY: u8 = 85;
+
+// ravif's speed setting runs from 1 (slowest, smallest) to 10 (fastest). We're encoding
+// on the request path, so we lean towards fast.
+const AVIF_SPEED: u8 = 8;
+
+const EPSG_WEB_MERCATOR: u16 = 3857;
+
+// The GeoTIFF key directory describing an EPSG:3857 raster: a header of (version, revision,
+// minor revision, key count), then one (key id, location, count, value) row per key, where a
+// location of 0 means the value is stored inline. See the GeoTIFF spec (OGC 19-008r4).
+#[rustfmt::skip]
+const GEO_KEY_DIRECTORY: [u16; 16] = [
+    1, 1, 0, 3,
+    1024, 0, 1, 1,                 // GTModelTypeGeoKey = ModelTypeProjected
+    1025, 0, 1, 1,                 // GTRasterTypeGeoKey = RasterPixelIsArea
+    3072, 0, 1, EPSG_WEB_MERCATOR, // ProjectedCSTypeGeoKey
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Avif,
+    GeoTiff,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "png" => Some(OutputFormat::Png),
+            "jpeg" | "jpg" => Some(OutputFormat::Jpeg),
+            "webp" => Some(OutputFormat::WebP),
+            "avif" => Some(OutputFormat::Avif),
+            "geotiff" | "tiff" => Some(OutputFormat::GeoTiff),
+            _ => None,
+        }
+    }
+
+    // Maps a media type from an Accept header to the format we'd produce for it
+    pub fn from_media_type(media_type: &str) -> Option<OutputFormat> {
+        [
+            OutputFormat::Png,
+            OutputFormat::Jpeg,
+            OutputFormat::WebP,
+            OutputFormat::Avif,
+            OutputFormat::GeoTiff,
+        ]
+        .into_iter()
+        .find(|f| f.content_type() == media_type)
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::GeoTiff => "image/tiff",
+        }
+    }
+}
+
+// Encodes the image in the given format. Quality (1-100) only applies to the lossy formats;
+// image's WebP encoder is lossless only, so it ignores it too. The metadata is only used by
+// formats that embed georeferencing information.
+pub fn encode(
+    image: RgbaImage,
+    format: OutputFormat,
+    quality: u8,
+    meta: &ImageMetadata,
+) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let quality = quality.clamp(1, 100);
+
+    match format {
+        OutputFormat::Png => DynamicImage::ImageRgba8(image)
+            .write_to(&mut Cursor::new(&mut buffer), image::ImageFormat::Png)
+            .with_context(|| "encoding PNG")?,
+        // JPEG has no alpha channel, so flatten down to RGB first
+        OutputFormat::Jpeg => DynamicImage::ImageRgba8(image)
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, quality))
+            .with_context(|| "encoding JPEG")?,
+        OutputFormat::WebP => image
+            .write_with_encoder(WebPEncoder::new_lossless(&mut buffer))
+            .with_context(|| "encoding WebP")?,
+        OutputFormat::Avif => image
+            .write_with_encoder(AvifEncoder::new_with_speed_quality(
+                &mut buffer,
+                AVIF_SPEED,
+                quality,
+            ))
+            .with_context(|| "encoding AVIF")?,
+        OutputFormat::GeoTiff => write_geotiff(&image, meta, &mut Cursor::new(&mut buffer))
+            .with_context(|| "encoding GeoTIFF")?,
+    }
+
+    Ok(buffer)
+}
+
+// Writes the image as a GeoTIFF in EPSG:3857. The ModelTiepoint ties the top-left corner
+// of the top-left pixel to its projected position, and ModelPixelScale gives the pixel size
+// in metres, which is all a GIS needs to place the image.
+fn write_geotiff(
+    image: &RgbaImage,
+    meta: &ImageMetadata,
+    writer: &mut Cursor<&mut Vec<u8>>,
+) -> tiff::TiffResult<()> {
+    let [min_x, _, _, max_y] = meta.bbox_epsg3857;
+
+    let mut tiff = TiffEncoder::new(writer)?;
+    let mut tiff_image = tiff.new_image_with_compression::<colortype::RGBA8, _>(
+        image.width(),
+        image.height(),
+        Deflate::default(),
+    )?;
+
+    let encoder = tiff_image.encoder();
+    encoder.write_tag(
+        Tag::ModelPixelScaleTag,
+        &[meta.metres_per_pixel, meta.metres_per_pixel, 0.0][..],
+    )?;
+    encoder.write_tag(
+        Tag::ModelTiepointTag,
+        &[0.0, 0.0, 0.0, min_x, max_y, 0.0][..],
+    )?;
+    encoder.write_tag(Tag::GeoKeyDirectoryTag, &GEO_KEY_DIRECTORY[..])?;
+
+    tiff_image.write_data(image.as_raw())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::coordinates::{
+        lat_long_and_image_size_to_bounding_box, LatLong, MAX_ZOOM, TILE_SIZE_PX,
+    };
+    use crate::tiles::TileSet;
+    use tiff::decoder::{Decoder, DecodingResult};
+
+    fn test_render() -> (RgbaImage, ImageMetadata) {
+        let tile_box = lat_long_and_image_size_to_bounding_box(
+            LatLong(46.6, 8.1),
+            0.5,
+            64,
+            TILE_SIZE_PX,
+            MAX_ZOOM,
+        );
+        let meta = ImageMetadata::for_render(&tile_box, &[TileSet::Osm], &Config::default());
+        let image = RgbaImage::from_pixel(
+            meta.width_px,
+            meta.height_px,
+            image::Rgba([10, 20, 30, 255]),
+        );
+        (image, meta)
+    }
+
+    #[test]
+    fn test_encode_png() {
+        let (image, meta) = test_render();
+        let bytes =
+            encode(image, OutputFormat::Png, DEFAULT_QUALITY, &meta).expect("I can encode a PNG");
+
+        let decoded = image::load_from_memory(&bytes).expect("I can decode my PNG");
+        assert_eq!(decoded.width(), meta.width_px);
+    }
+
+    #[test]
+    fn test_encode_lossy_formats() {
+        for (format, expected) in [
+            (OutputFormat::Jpeg, image::ImageFormat::Jpeg),
+            (OutputFormat::WebP, image::ImageFormat::WebP),
+        ] {
+            let (image, meta) = test_render();
+            let bytes = encode(image, format, 50, &meta).expect("I can encode my image");
+
+            assert_eq!(image::guess_format(&bytes).unwrap(), expected);
+            let decoded = image::load_from_memory(&bytes).expect("I can decode my image");
+            assert_eq!(decoded.height(), meta.height_px);
+        }
+
+        // We can't decode AVIF without the native dav1d decoder, so just check the container
+        let (image, meta) = test_render();
+        let bytes = encode(image, OutputFormat::Avif, 50, &meta).expect("I can encode an AVIF");
+        assert_eq!(&bytes[4..12], b"ftypavif");
+    }
+
+    #[test]
+    fn test_format_from_media_type() {
+        assert_eq!(
+            OutputFormat::from_media_type("image/webp"),
+            Some(OutputFormat::WebP)
+        );
+        assert_eq!(
+            OutputFormat::from_media_type("image/tiff"),
+            Some(OutputFormat::GeoTiff)
+        );
+        assert_eq!(OutputFormat::from_media_type("text/html"), None);
+    }
+
+    #[test]
+    fn test_encode_geotiff() {
+        let (image, meta) = test_render();
+        let bytes = encode(image, OutputFormat::GeoTiff, DEFAULT_QUALITY, &meta)
+            .expect("I can encode a GeoTIFF");
+
+        let mut decoder = Decoder::new(Cursor::new(bytes)).expect("I can read my GeoTIFF");
+        assert_eq!(
+            decoder.dimensions().unwrap(),
+            (meta.width_px, meta.height_px)
+        );
+
+        let scale = decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag).unwrap();
+        assert_eq!(
+            scale,
+            vec![meta.metres_per_pixel, meta.metres_per_pixel, 0.0]
+        );
+
+        let tiepoint = decoder.get_tag_f64_vec(Tag::ModelTiepointTag).unwrap();
+        assert_eq!(tiepoint[3], meta.bbox_epsg3857[0]);
+        assert_eq!(tiepoint[4], meta.bbox_epsg3857[3]);
+
+        let geo_keys = decoder.get_tag_u16_vec(Tag::GeoKeyDirectoryTag).unwrap();
+        assert_eq!(geo_keys[3], 3);
+        assert_eq!(geo_keys.last(), Some(&EPSG_WEB_MERCATOR));
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(pixels) => assert_eq!(&pixels[0..4], &[10, 20, 30, 255]),
+            _ => panic!("Expected 8-bit RGBA pixels"),
+        }
+    }
+}
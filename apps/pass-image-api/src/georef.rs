@@ -0,0 +1,174 @@
+// ! # georef
+// !
+// ! Describes where a rendered image sits on the earth, so that callers can lay it
+// ! over a map or load it into a GIS without redoing our projection maths.
+// !
+
+use crate::config::Config;
+use crate::coordinates::{web_mercator_metres_per_pixel, ConstrainedTileBox};
+use crate::tiles::TileSet;
+use serde::Serialize;
+
+// Georeferencing information for a single render
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageMetadata {
+    pub width_px: u32,
+    pub height_px: u32,
+    // The zoom level of the tiles the image was made from
+    pub zoom: u32,
+    // How many zoom levels past the tileset's deepest tiles we scaled up
+    pub overzoom: u32,
+    // [west, south, east, north] in decimal degrees
+    pub bbox_wgs84: [f64; 4],
+    // [min x, min y, max x, max y] in EPSG:3857 metres
+    pub bbox_epsg3857: [f64; 4],
+    // The pixel size in EPSG:3857 metres
+    pub metres_per_pixel: f64,
+    // The pixel size on the ground at the center of the image
+    pub ground_metres_per_pixel: f64,
+    // The tilesets the image is made from, bottom layer first and then in the order we'd try them
+    pub tilesets: Vec<String>,
+    pub attribution: String,
+    pub tile_count: usize,
+}
+
+impl ImageMetadata {
+    // Works out the metadata for the image fetch_image would render for this tile box from
+    // the given tilesets
+    pub fn for_render(
+        tile_box: &ConstrainedTileBox,
+        tilesets: &[TileSet],
+        config: &Config,
+    ) -> Self {
+        let (width_px, height_px) = tile_box.output_size_px();
+        let zoom = tile_box.tile_box.top_left.z;
+        let tile_size = tile_box.tile_size_px;
+
+        // The bounds come from the image before any overzoom scaling
+        let (min_x, max_y) = tile_box.image_origin().to_web_mercator(tile_size);
+        let (max_x, min_y) = tile_box.image_bottom_right().to_web_mercator(tile_size);
+
+        let metres_per_pixel =
+            web_mercator_metres_per_pixel(zoom, tile_size) / tile_box.overzoom_factor() as f64;
+
+        ImageMetadata {
+            width_px,
+            height_px,
+            zoom,
+            overzoom: tile_box.overzoom,
+            bbox_wgs84: tile_box.bbox_wgs84(),
+            bbox_epsg3857: [min_x, min_y, max_x, max_y],
+            metres_per_pixel,
+            ground_metres_per_pixel: metres_per_pixel * tile_box.center.0.to_radians().cos(),
+            tilesets: tilesets.iter().map(|t| t.name().to_string()).collect(),
+            attribution: tilesets
+                .iter()
+                .map(|t| config.attribution(*t))
+                .filter(|a| !a.is_empty())
+                .collect::<Vec<_>>()
+                .join("; "),
+            tile_count: tile_box.tile_box.tile_ids().len(),
+        }
+    }
+
+    // Renders an ESRI world file (.pgw for PNGs) in EPSG:3857. World files reference the
+    // center of the top-left pixel rather than its corner.
+    pub fn world_file(&self) -> String {
+        let [min_x, _, _, max_y] = self.bbox_epsg3857;
+        let half_pixel = self.metres_per_pixel / 2.0;
+
+        format!(
+            "{0:.10}\n0.0000000000\n0.0000000000\n{1:.10}\n{2:.10}\n{3:.10}\n",
+            self.metres_per_pixel,
+            -self.metres_per_pixel,
+            min_x + half_pixel,
+            max_y - half_pixel
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinates::{
+        lat_long_and_image_size_to_bounding_box, LatLong, MAX_ZOOM, TILE_SIZE_PX,
+    };
+
+    #[test]
+    fn test_metadata_for_render() {
+        let center = LatLong(46.655559, 8.102121);
+        let tile_box =
+            lat_long_and_image_size_to_bounding_box(center, 3.0, 1024, TILE_SIZE_PX, MAX_ZOOM);
+        let meta = ImageMetadata::for_render(
+            &tile_box,
+            &[TileSet::Swisstopo, TileSet::Osm],
+            &Config::default(),
+        );
+
+        assert_eq!((meta.width_px, meta.height_px), tile_box.inner_size_px);
+        assert_eq!(meta.zoom, tile_box.tile_box.top_left.z);
+        assert_eq!(meta.tilesets, vec!["swisstopo", "osm"]);
+        assert_eq!(
+            meta.attribution,
+            "© swisstopo; © OpenStreetMap contributors"
+        );
+
+        // The center should fall inside the bounding box
+        let [west, south, east, north] = meta.bbox_wgs84;
+        assert!(west < center.1 && center.1 < east);
+        assert!(south < center.0 && center.0 < north);
+
+        // The projected bbox should be exactly width x height pixels
+        let [min_x, min_y, max_x, max_y] = meta.bbox_epsg3857;
+        assert!(((max_x - min_x) / meta.metres_per_pixel - meta.width_px as f64).abs() < 1e-6);
+        assert!(((max_y - min_y) / meta.metres_per_pixel - meta.height_px as f64).abs() < 1e-6);
+
+        // Ground resolution shrinks away from the equator
+        assert!(meta.ground_metres_per_pixel < meta.metres_per_pixel);
+    }
+
+    #[test]
+    fn test_metadata_for_overzoomed_render() {
+        let center = LatLong(46.655559, 8.102121);
+        let deep =
+            lat_long_and_image_size_to_bounding_box(center, 0.1, 1024, TILE_SIZE_PX, MAX_ZOOM);
+        let capped = lat_long_and_image_size_to_bounding_box(center, 0.1, 1024, TILE_SIZE_PX, 16);
+        let deep_meta = ImageMetadata::for_render(&deep, &[TileSet::Osm], &Config::default());
+        let meta = ImageMetadata::for_render(&capped, &[TileSet::Osm], &Config::default());
+
+        // The output is described at the resolution we scaled up to
+        assert_eq!(meta.zoom, 16);
+        assert_eq!(meta.overzoom, capped.overzoom);
+        assert_eq!((meta.width_px, meta.height_px), capped.output_size_px());
+        assert!((meta.metres_per_pixel - deep_meta.metres_per_pixel).abs() < 1e-9);
+
+        let [min_x, _, max_x, _] = meta.bbox_epsg3857;
+        assert!(((max_x - min_x) / meta.metres_per_pixel - meta.width_px as f64).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_world_file() {
+        let tile_box = lat_long_and_image_size_to_bounding_box(
+            LatLong(0.0, 0.0),
+            1.0,
+            512,
+            TILE_SIZE_PX,
+            MAX_ZOOM,
+        );
+        let meta = ImageMetadata::for_render(&tile_box, &[TileSet::Osm], &Config::default());
+
+        let lines: Vec<f64> = meta
+            .world_file()
+            .lines()
+            .map(|l| l.parse().expect("world file lines are numbers"))
+            .collect();
+
+        assert_eq!(lines.len(), 6);
+        assert!((lines[0] - meta.metres_per_pixel).abs() < 1e-9);
+        assert_eq!(lines[1], 0.0);
+        assert_eq!(lines[2], 0.0);
+        assert!((lines[3] + meta.metres_per_pixel).abs() < 1e-9);
+        assert!((lines[4] - (meta.bbox_epsg3857[0] + meta.metres_per_pixel / 2.0)).abs() < 1e-6);
+        assert!((lines[5] - (meta.bbox_epsg3857[3] - meta.metres_per_pixel / 2.0)).abs() < 1e-6);
+    }
+}
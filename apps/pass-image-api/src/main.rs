@@ -1,17 +1,375 @@
+// Handlers bail out early with the response to send, which is bigger than clippy would like an
+// error to be
+#![allow(clippy::result_large_err)]
+
 use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::coordinates::LatLong;
-use crate::tiles::fetch_image_from_point;
-use actix_web::{get, http::header::ContentType, web, App, HttpResponse, HttpServer, Responder};
+use crate::config::Config;
+use crate::coordinates::{
+    lat_long_and_image_size_to_bounding_box, zoom_for_radius, ConstrainedTileBox, ImagePixel,
+    LatLong, TileId, MAX_OVERZOOM,
+};
+use crate::coverage::Coverage;
+use crate::encoding::{OutputFormat, DEFAULT_QUALITY};
+use crate::georef::ImageMetadata;
+use crate::recording::{count_recordings, Cassette, Recorder, Replay};
+use crate::tiles::{fetch_image, fetch_single_tile, Layer, OutsideCoverage};
+use actix_web::http::header::{Accept, ContentType, Header, CACHE_CONTROL, VARY};
+use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use log::{error, info, warn};
 use opentelemetry_instrumentation_actix_web::RequestTracing;
-use log::{info, warn};
+use serde::{Deserialize, Serialize};
 use tiles::TileSet;
+mod blend;
+mod config;
 mod coordinates;
+mod coverage;
+mod debug_tiles;
+mod directory;
+mod encoding;
+mod georef;
+mod mbtiles;
+mod pmtiles;
+mod recording;
+mod tile_cache;
 mod tiles;
 
+mod template;
+mod wms;
+mod wmts;
+
 mod telemetry_conf;
 use telemetry_conf::init_otel;
 
+// The radius around the center point we cover when the caller doesn't give us one
+const DEFAULT_RADIUS_KM: f64 = 1.0;
+
+// Tells the caller how many zoom levels past the tileset's deepest tiles we had to scale up
+const OVERZOOM_HEADER: &str = "X-Overzoom";
+
+// Tells the caller which tilesets supplied the tiles for an image, bottom layer first
+const TILE_SOURCES_HEADER: &str = "X-Tile-Sources";
+
+// Tells the caller which file a ?record=true render's tiles were recorded to
+const TILE_RECORDING_HEADER: &str = "X-Tile-Recording";
+
+// Where ?record=true renders have to send TILE_RECORDING_TOKEN, so that only we can fill
+// the disk with recordings
+const RECORDING_TOKEN_HEADER: &str = "X-Recording-Token";
+
+// The most recordings we keep in TILE_RECORDINGS_DIR. Past this, renders stop recording
+// until someone clears the old ones out.
+const MAX_RECORDINGS: usize = 100;
+
+// How long browsers and caches in between may keep the tiles we proxy. OSM's tile usage
+// policy asks for at least a week.
+const TILE_MAX_AGE_SECS: u32 = 7 * 24 * 60 * 60;
+
+// How long they may remember that a tileset has no tile somewhere
+const MISSING_TILE_MAX_AGE_SECS: u32 = 60 * 60;
+
+// The tileset chain we use when neither the request nor DEFAULT_TILESETS gives us one
+const DEFAULT_TILESETS: &str = "osm";
+
+// The largest pixel density multiplier we'll render at, e.g. 2 for "retina" screens
+const MAX_SCALE: u32 = 2;
+
+// Server-wide settings, read from the environment at startup
+struct AppState {
+    default_tilesets: Vec<TileSet>,
+    config: Config,
+    // Where ?record=true renders save their tiles, from TILE_RECORDINGS_DIR, and the token
+    // they have to send, from TILE_RECORDING_TOKEN. Recording is off without both.
+    recordings_dir: Option<PathBuf>,
+    recording_token: Option<String>,
+    // A recording from TILE_REPLAY to serve every render's tiles from, instead of upstream
+    replay: Option<Cassette>,
+}
+
+impl AppState {
+    fn from_env(config: Config) -> anyhow::Result<Self> {
+        let names = env::var("DEFAULT_TILESETS").unwrap_or_else(|_| DEFAULT_TILESETS.to_string());
+        let mut default_tilesets = TileSet::parse_chain(&names);
+        if default_tilesets.is_empty() {
+            warn!(
+                "DEFAULT_TILESETS '{0}' has no tilesets we know, using '{1}'",
+                names, DEFAULT_TILESETS
+            );
+            default_tilesets = TileSet::parse_chain(DEFAULT_TILESETS);
+        }
+
+        let replay = match env::var("TILE_REPLAY") {
+            Ok(path) => Some(Cassette::load(&PathBuf::from(path))?),
+            Err(_) => None,
+        };
+
+        Ok(AppState {
+            default_tilesets,
+            config,
+            recordings_dir: env::var("TILE_RECORDINGS_DIR").ok().map(PathBuf::from),
+            recording_token: env::var("TILE_RECORDING_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            replay,
+        })
+    }
+}
+
+// Picks the named layer to draw from WMTS-style tilesets, for every layer that has one
+fn select_source_layer(
+    layers: &mut [Layer],
+    name: &str,
+    state: &AppState,
+) -> Result<(), HttpResponse> {
+    let layered: Vec<&mut Layer> = layers
+        .iter_mut()
+        .filter(|l| l.tilesets.iter().any(|t| t.supports_layers()))
+        .collect();
+    if layered.is_empty() {
+        return Err(HttpResponse::BadRequest()
+            .body("None of the requested tilesets have layers to pick from"));
+    }
+    for layer in layered {
+        layer
+            .select_source(Some(name), &state.config)
+            .map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?;
+    }
+    Ok(())
+}
+
+// Asks every layer for an older edition of the map, as Layer::select_time does. There has to
+// be at least one layer with a time dimension, and each of them has to have the edition.
+fn select_time(layers: &mut [Layer], time: &str, state: &AppState) -> Result<(), HttpResponse> {
+    let mut timed = false;
+    for layer in layers.iter_mut() {
+        timed |= layer
+            .select_time(time, &state.config)
+            .map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?;
+    }
+    if !timed {
+        return Err(HttpResponse::BadRequest()
+            .body("None of the requested tilesets have older editions to ask for a time from"));
+    }
+    Ok(())
+}
+
+// The parameters describing a single render, shared by the image and metadata routes
+struct RenderParams {
+    center: LatLong,
+    radius_km: f64,
+    size_px: u32,
+    // The layers to composite, bottom first. Never empty, and neither are their tilesets.
+    layers: Vec<Layer>,
+    scale: u32,
+}
+
+impl RenderParams {
+    fn new(
+        center: LatLong,
+        radius_km: Option<f64>,
+        size_px: u32,
+        tilesets: Option<&str>,
+        layers: Option<&str>,
+        scale: Option<u32>,
+        state: &AppState,
+    ) -> Result<Self, HttpResponse> {
+        let layers = match layers {
+            Some(layers) => Layer::parse_list(layers, &state.config)
+                .map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?,
+            None => {
+                // Unknown tilesets are skipped, and if that leaves nothing we use the
+                // default chain
+                let tilesets = tilesets
+                    .map(TileSet::parse_chain)
+                    .filter(|chain| !chain.is_empty())
+                    .unwrap_or_else(|| state.default_tilesets.clone());
+                let mut layer = Layer::opaque(tilesets);
+                layer
+                    .select_source(None, &state.config)
+                    .map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?;
+                vec![layer]
+            }
+        };
+
+        // Some tilesets need the config to say where their tiles come from
+        if let Some(tileset) = layers
+            .iter()
+            .flat_map(|l| &l.tilesets)
+            .find(|t| !state.config.is_available(**t))
+        {
+            return Err(HttpResponse::BadRequest().body(format!(
+                "Tileset '{0}' isn't configured on this server",
+                tileset.name()
+            )));
+        }
+
+        let scale = scale.unwrap_or(1);
+        if !(1..=MAX_SCALE).contains(&scale) {
+            return Err(HttpResponse::BadRequest().body(format!(
+                "Unsupported scale {0}, expected 1 to {1}",
+                scale, MAX_SCALE
+            )));
+        }
+
+        let radius_km = radius_km.unwrap_or(DEFAULT_RADIUS_KM);
+        if !radius_km.is_finite() || radius_km <= 0.0 {
+            return Err(HttpResponse::BadRequest().body(format!(
+                "Invalid radius {0}, expected a positive number of kilometres",
+                radius_km
+            )));
+        }
+
+        let params = RenderParams {
+            center,
+            radius_km,
+            size_px,
+            layers,
+            scale,
+        };
+
+        // Scaling tiles up only goes so far, so a tiny radius over a big image has nothing
+        // to draw it from
+        let max_zoom = params.max_zoom(&state.config);
+        if params.zoom() > max_zoom + MAX_OVERZOOM {
+            return Err(HttpResponse::BadRequest().body(format!(
+                "Radius {0}km is too small for a {1}px image with tiles down to z{2}",
+                radius_km, size_px, max_zoom
+            )));
+        }
+        Ok(params)
+    }
+
+    fn from_request(
+        path: (f64, f64, u32),
+        query: &HashMap<String, String>,
+        state: &AppState,
+    ) -> Result<Self, HttpResponse> {
+        let (long, lat, size_px) = path;
+
+        // Extract optional parameters from the query map
+        RenderParams::new(
+            LatLong(lat, long),
+            query.get("radius").and_then(|r| r.parse().ok()),
+            size_px,
+            query.get("tileset").map(String::as_str),
+            query.get("layers").map(String::as_str),
+            query.get("scale").and_then(|s| s.parse().ok()),
+            state,
+        )?
+        .with_source_layer(query.get("layer").map(String::as_str), state)?
+        .with_time(query.get("time").map(String::as_str), state)
+    }
+
+    // Picks which layer to draw from WMTS-style tilesets, e.g. ?layer=swissimage, for every
+    // layer of the render that has one
+    fn with_source_layer(
+        mut self,
+        name: Option<&str>,
+        state: &AppState,
+    ) -> Result<Self, HttpResponse> {
+        if let Some(name) = name {
+            select_source_layer(&mut self.layers, name, state)?;
+        }
+        Ok(self)
+    }
+
+    // Asks every layer for an older edition of the map, e.g. ?time=1990
+    fn with_time(mut self, time: Option<&str>, state: &AppState) -> Result<Self, HttpResponse> {
+        if let Some(time) = time {
+            select_time(&mut self.layers, time, state)?;
+        }
+        Ok(self)
+    }
+
+    // Every tileset the render might use, bottom layer first
+    fn tilesets(&self) -> Vec<TileSet> {
+        let mut tilesets = Vec::new();
+        for tileset in self.layers.iter().flat_map(|l| &l.tilesets) {
+            if !tilesets.contains(tileset) {
+                tilesets.push(*tileset);
+            }
+        }
+        tilesets
+    }
+
+    // The tile box is laid out for the first tileset of the bottom layer; the others fill in
+    // where they can. We stop at the shallowest max zoom of the layers' first tilesets (or
+    // the source layers picked from them) so that every layer has tiles. At scale > 1 we cover
+    // the same area with proportionally more pixels.
+    fn tile_box(&self, config: &Config) -> ConstrainedTileBox {
+        lat_long_and_image_size_to_bounding_box(
+            self.center,
+            self.radius_km,
+            self.size_px * self.scale,
+            self.tile_size(),
+            self.max_zoom(config),
+        )
+    }
+
+    // The deepest zoom we can take tiles from for every layer
+    fn max_zoom(&self, config: &Config) -> u32 {
+        self.layers
+            .iter()
+            .map(|l| l.max_zoom(l.tilesets[0], config))
+            .min()
+            .unwrap_or(config.max_zoom(self.layers[0].tilesets[0]))
+    }
+
+    // The edge length of the tiles we'll mosaic, going by the bottom layer's first tileset
+    fn tile_size(&self) -> u32 {
+        self.layers[0].tilesets[0].tile_size_for_scale(self.scale)
+    }
+
+    // The zoom level the radius needs for enough pixels, before any overzoom
+    fn zoom(&self) -> u32 {
+        zoom_for_radius(self.radius_km, self.size_px * self.scale, self.tile_size())
+    }
+}
+
+// A request to map a pixel within a render back to a point on the earth. The render
+// is described with the same parameters as /images/{long}/{lat}/{size_px}, and x/y are
+// measured in pixels from the top-left corner of the returned image.
+#[derive(Deserialize)]
+struct LocateRequest {
+    long: f64,
+    lat: f64,
+    size_px: u32,
+    radius: Option<f64>,
+    tileset: Option<String>,
+    layers: Option<String>,
+    scale: Option<u32>,
+    layer: Option<String>,
+    time: Option<String>,
+    x: f64,
+    y: f64,
+}
+
+#[derive(Serialize)]
+struct LocateResponse {
+    lat: f64,
+    long: f64,
+}
+
+// What we tell callers about each tileset on /tilesets
+#[derive(Serialize)]
+struct TileSetDescription<'a> {
+    name: &'static str,
+    attribution: &'a str,
+    tile_size_px: u32,
+    max_zoom: u32,
+    retina: bool,
+    coverage: Coverage,
+    // The layers we can pick with ?layer=, for WMTS-style tilesets, default first
+    layers: Vec<String>,
+    // The editions we can ask for with ?time=, for tilesets with a time dimension
+    times: Vec<&'a str>,
+    // Whether the tileset is in the default chain
+    default: bool,
+}
+
 async fn index() -> impl Responder {
     "Nothing here"
 }
@@ -22,40 +380,406 @@ async fn health() -> impl Responder {
         .body("{\"status\": \"ok\"}")
 }
 
+// Renders the georeferencing metadata for a render, either as JSON or as an ESRI world file
+fn metadata_response(params: &RenderParams, format: &str, config: &Config) -> HttpResponse {
+    let meta = ImageMetadata::for_render(&params.tile_box(config), &params.tilesets(), config);
+
+    match format {
+        "json" => HttpResponse::Ok().json(meta),
+        "pgw" => HttpResponse::Ok()
+            .content_type(ContentType::plaintext())
+            .body(meta.world_file()),
+        _ => HttpResponse::BadRequest().body(format!(
+            "Unsupported metadata format '{0}', expected json or pgw",
+            format
+        )),
+    }
+}
+
+// Picks the output format for an image. An explicit ?format= wins; otherwise we take the
+// most preferred type in the Accept header that we can produce, falling back to PNG.
+fn negotiate_format(
+    query: &HashMap<String, String>,
+    req: &HttpRequest,
+) -> Result<OutputFormat, HttpResponse> {
+    if let Some(name) = query.get("format") {
+        return OutputFormat::from_name(name).ok_or_else(|| {
+            HttpResponse::BadRequest().body(format!("Unsupported output format '{0}'", name))
+        });
+    }
+
+    let format = Accept::parse(req)
+        .ok()
+        .and_then(|accept| {
+            accept
+                .ranked()
+                .iter()
+                .find_map(|mime| OutputFormat::from_media_type(mime.essence_str()))
+        })
+        .unwrap_or(OutputFormat::Png);
+
+    Ok(format)
+}
+
 #[get("/images/{long}/{lat}/{size_px}")]
 async fn get_image(
+    req: HttpRequest,
     path: web::Path<(f64, f64, u32)>,
     query: web::Query<HashMap<String, String>>,
+    state: web::Data<AppState>,
 ) -> impl Responder {
-    let (long, lat, size_px) = path.into_inner();
-
-    // Extract optional parameters from the query map
-    let radius = query
-        .get("radius")
-        .and_then(|r| r.parse().ok())
-        .unwrap_or(1.0);
-    let tileset = query
-        .get("tileset")
-        .map(|t| match t.as_str() {
-            "swisstopo" => TileSet::Swisstopo,
-            _ => TileSet::Osm,
-        })
-        .unwrap_or(TileSet::Osm);
+    let params = match RenderParams::from_request(path.into_inner(), &query, &state) {
+        Ok(params) => params,
+        Err(response) => return response,
+    };
+
+    // ?meta=json or ?meta=pgw asks for the metadata instead of the image itself
+    if let Some(format) = query.get("meta") {
+        return metadata_response(&params, format, &state.config);
+    }
+
+    let format = match negotiate_format(&query, &req) {
+        Ok(format) => format,
+        Err(response) => return response,
+    };
+    let quality = query
+        .get("quality")
+        .and_then(|q| q.parse().ok())
+        .unwrap_or(DEFAULT_QUALITY);
 
     info!(
-        latitude = lat,
-        longitude = long;
+        latitude = params.center.0,
+        longitude = params.center.1;
         "Fetching image"
     );
 
-    match fetch_image_from_point(LatLong(lat, long), radius, size_px, tileset).await {
-        Ok(image) => HttpResponse::Ok()
-            .content_type(ContentType::png())
-            .body(image),
-        Err(_) => HttpResponse::InternalServerError().into(),
+    let record = query.get("record").is_some_and(|r| r == "true");
+    let recordings_dir = match record {
+        true => match recordings_dir(&req, &state).await {
+            Ok(dir) => Some(dir),
+            Err(response) => return response,
+        },
+        false => None,
+    };
+
+    let tile_box = params.tile_box(&state.config);
+    let mut recording = None;
+    let result = if let Some(cassette) = &state.replay {
+        let tiles = Replay::new(&state.config, cassette);
+        fetch_image(&tiles, &params.layers, &tile_box, format, quality).await
+    } else if let Some(dir) = recordings_dir {
+        let tiles = Recorder::new(&state.config);
+        let result = fetch_image(&tiles, &params.layers, &tile_box, format, quality).await;
+        // Keep the recording whether or not the render worked, since failures are often
+        // what we want to reproduce
+        recording = save_recording(tiles, &req, &dir).await;
+        result
+    } else {
+        fetch_image(&state.config, &params.layers, &tile_box, format, quality).await
+    };
+
+    match result {
+        Ok(image) => {
+            let sources: Vec<&str> = image.tilesets.iter().map(|t| t.name()).collect();
+
+            let mut response = HttpResponse::Ok();
+            response
+                .content_type(format.content_type())
+                .insert_header((VARY, "Accept"))
+                .insert_header((TILE_SOURCES_HEADER, sources.join(",")));
+            if tile_box.overzoom > 0 {
+                response.insert_header((OVERZOOM_HEADER, tile_box.overzoom.to_string()));
+            }
+            if let Some(name) = recording {
+                response.insert_header((TILE_RECORDING_HEADER, name));
+            }
+            response.body(image.bytes)
+        }
+        Err(e) => {
+            let (mut response, body) = match e.downcast_ref::<OutsideCoverage>() {
+                Some(outside) => (HttpResponse::UnprocessableEntity(), outside.to_string()),
+                None => (HttpResponse::InternalServerError(), String::new()),
+            };
+            if let Some(name) = recording {
+                response.insert_header((TILE_RECORDING_HEADER, name));
+            }
+            response.body(body)
+        }
+    }
+}
+
+// Checks that a ?record=true render may record its tiles: recording has to be enabled, the
+// caller has to send the recording token, and there has to be room for another recording.
+// Returns the directory to save it in.
+async fn recordings_dir(req: &HttpRequest, state: &AppState) -> Result<PathBuf, HttpResponse> {
+    let (Some(dir), Some(token)) = (&state.recordings_dir, &state.recording_token) else {
+        return Err(HttpResponse::BadRequest().body(
+            "Recording isn't enabled on this server, set TILE_RECORDINGS_DIR and TILE_RECORDING_TOKEN",
+        ));
+    };
+    let sent = req
+        .headers()
+        .get(RECORDING_TOKEN_HEADER)
+        .map_or(&[][..], |value| value.as_bytes());
+    if !same_token(sent, token.as_bytes()) {
+        return Err(HttpResponse::Forbidden().body(format!(
+            "Recording needs the right {0} header",
+            RECORDING_TOKEN_HEADER
+        )));
+    }
+
+    let listed = dir.clone();
+    let count = web::block(move || count_recordings(&listed))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|count| count);
+    match count {
+        Ok(count) if count >= MAX_RECORDINGS => {
+            Err(HttpResponse::InsufficientStorage().body(format!(
+                "There are already {0} tile recordings; clear some out to record more",
+                count
+            )))
+        }
+        Ok(_) => Ok(dir.clone()),
+        Err(e) => {
+            error!("Couldn't count tile recordings: {0:#}", e);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+// Saves the tiles a request used to a new recording in the directory, returning its name if
+// that worked
+async fn save_recording(tiles: Recorder<'_>, req: &HttpRequest, dir: &Path) -> Option<String> {
+    let name = recording_name();
+    let cassette = tiles.into_cassette(&req.uri().to_string());
+    let path = dir.join(&name);
+    let saved = web::block(move || cassette.save(&path))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|saved| saved);
+    match saved {
+        Ok(()) => Some(name),
+        Err(e) => {
+            error!("Couldn't save tile recording: {0:#}", e);
+            None
+        }
+    }
+}
+
+// Compares a token without stopping at the first difference, so that how long we take to
+// turn a caller away doesn't give the token away
+fn same_token(sent: &[u8], token: &[u8]) -> bool {
+    sent.len() == token.len()
+        && sent
+            .iter()
+            .zip(token)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// A file name for a new tile recording, unique enough for the handful we take
+fn recording_name() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("tiles-{0}-{1:09}.json", now.as_secs(), now.subsec_nanos())
+}
+
+// Splits the last segment of a tile URL, e.g. "1449@2x.png", into the tile's y and whether
+// it asks for a retina tile. Any extension is ignored; tiles go out in whatever format the
+// tileset serves them in.
+fn parse_tile_y(segment: &str) -> Option<(u32, bool)> {
+    let stem = segment.split_once('.').map_or(segment, |(stem, _)| stem);
+    match stem.strip_suffix("@2x") {
+        Some(y) => Some((y.parse().ok()?, true)),
+        None => Some((stem.parse().ok()?, false)),
+    }
+}
+
+// Proxies a single XYZ tile from a tileset, so that map widgets can share our tile sources.
+// Tiles come from the same places as renders' do: the tile cache and upstream, a recording
+// being replayed, or upstream into a new recording with ?record=true.
+#[get("/tiles/{tileset}/{z}/{x}/{y}")]
+async fn get_tile(
+    req: HttpRequest,
+    path: web::Path<(String, u32, u32, String)>,
+    query: web::Query<HashMap<String, String>>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (name, z, x, y) = path.into_inner();
+    let Some(tileset) = TileSet::from_name(&name).filter(|t| state.config.is_available(*t)) else {
+        return HttpResponse::NotFound()
+            .body(format!("There's no tileset '{0}' on this server", name));
+    };
+    let Some((y, retina)) = parse_tile_y(&y) else {
+        return HttpResponse::BadRequest().body(format!(
+            "Invalid tile y '{0}', expected e.g. 1449, 1449.png or 1449@2x.png",
+            y
+        ));
+    };
+    let tile = TileId { z, x, y };
+    if !tile.is_valid() {
+        return HttpResponse::NotFound().body(format!("There's no tile {0}/{1}/{2}", z, x, y));
+    }
+    if retina && !tileset.supports_retina() {
+        return HttpResponse::BadRequest()
+            .body(format!("{0} doesn't serve retina tiles", tileset.name()));
+    }
+
+    let mut layers = vec![Layer::opaque(vec![tileset])];
+    if let Err(e) = layers[0].select_source(None, &state.config) {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    if let Some(name) = query.get("layer") {
+        if let Err(response) = select_source_layer(&mut layers, name, &state) {
+            return response;
+        }
+    }
+    if let Some(time) = query.get("time") {
+        if let Err(response) = select_time(&mut layers, time, &state) {
+            return response;
+        }
+    }
+
+    let record = query.get("record").is_some_and(|r| r == "true");
+    let recordings_dir = match record {
+        true => match recordings_dir(&req, &state).await {
+            Ok(dir) => Some(dir),
+            Err(response) => return response,
+        },
+        false => None,
+    };
+
+    let tile_size = if retina {
+        tileset.tile_size() * 2
+    } else {
+        tileset.tile_size()
+    };
+    let mut recording = None;
+    let result = if let Some(cassette) = &state.replay {
+        let tiles = Replay::new(&state.config, cassette);
+        fetch_single_tile(&tiles, &layers[0], tile, tile_size).await
+    } else if let Some(dir) = recordings_dir {
+        let tiles = Recorder::new(&state.config);
+        let result = fetch_single_tile(&tiles, &layers[0], tile, tile_size).await;
+        recording = save_recording(tiles, &req, &dir).await;
+        result
+    } else {
+        fetch_single_tile(&state.config, &layers[0], tile, tile_size).await
+    };
+
+    let (mut response, body) = match result {
+        Ok(Some((_, fetched))) => {
+            let mut response = HttpResponse::Ok();
+            response
+                .content_type(fetched.format.content_type())
+                .insert_header((
+                    CACHE_CONTROL,
+                    format!("public, max-age={0}", TILE_MAX_AGE_SECS),
+                ));
+            (response, fetched.bytes)
+        }
+        Ok(None) => {
+            let mut response = HttpResponse::NotFound();
+            response.insert_header((
+                CACHE_CONTROL,
+                format!("public, max-age={0}", MISSING_TILE_MAX_AGE_SECS),
+            ));
+            let body = format!("{0} has no tile {1}/{2}/{3}", tileset.name(), z, x, y);
+            (response, body.into())
+        }
+        // The tile server let us down, rather than the caller asking for the wrong thing
+        Err(_) => (HttpResponse::BadGateway(), web::Bytes::new()),
+    };
+    if let Some(name) = recording {
+        response.insert_header((TILE_RECORDING_HEADER, name));
+    }
+    response.body(body)
+}
+
+#[get("/images/{long}/{lat}/{size_px}/meta")]
+async fn get_image_meta(
+    path: web::Path<(f64, f64, u32)>,
+    query: web::Query<HashMap<String, String>>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    match RenderParams::from_request(path.into_inner(), &query, &state) {
+        Ok(params) => metadata_response(&params, "json", &state.config),
+        Err(response) => response,
+    }
+}
+
+#[get("/images/{long}/{lat}/{size_px}/meta.pgw")]
+async fn get_image_world_file(
+    path: web::Path<(f64, f64, u32)>,
+    query: web::Query<HashMap<String, String>>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    match RenderParams::from_request(path.into_inner(), &query, &state) {
+        Ok(params) => metadata_response(&params, "pgw", &state.config),
+        Err(response) => response,
     }
 }
 
+#[post("/images/locate")]
+async fn locate(request: web::Json<LocateRequest>, state: web::Data<AppState>) -> impl Responder {
+    let params = match RenderParams::new(
+        LatLong(request.lat, request.long),
+        request.radius,
+        request.size_px,
+        request.tileset.as_deref(),
+        request.layers.as_deref(),
+        request.scale,
+        &state,
+    )
+    .and_then(|params| params.with_source_layer(request.layer.as_deref(), &state))
+    .and_then(|params| params.with_time(request.time.as_deref(), &state))
+    {
+        Ok(params) => params,
+        Err(response) => return response,
+    };
+    let tile_box = params.tile_box(&state.config);
+
+    // Make sure the pixel is actually within the image we would have rendered
+    let pixel = ImagePixel {
+        x: request.x,
+        y: request.y,
+    };
+    if !tile_box.contains_image_pixel(pixel) {
+        let (width, height) = tile_box.output_size_px();
+        return HttpResponse::BadRequest().body(format!(
+            "Pixel ({0}, {1}) is outside of the {2}x{3} image",
+            request.x, request.y, width, height
+        ));
+    }
+
+    let LatLong(lat, long) = tile_box.image_pixel_to_lat_long(pixel);
+
+    HttpResponse::Ok().json(LocateResponse { lat, long })
+}
+
+#[get("/tilesets")]
+async fn get_tilesets(state: web::Data<AppState>) -> impl Responder {
+    let tilesets: Vec<TileSetDescription> = TileSet::ALL
+        .iter()
+        .filter(|t| state.config.is_available(**t))
+        .map(|t| TileSetDescription {
+            name: t.name(),
+            attribution: state.config.attribution(*t),
+            tile_size_px: t.tile_size(),
+            max_zoom: state.config.max_zoom(*t),
+            retina: t.supports_retina(),
+            coverage: state.config.coverage(*t),
+            layers: state.config.source_layer_names(*t),
+            times: state.config.available_times(*t),
+            default: state.default_tilesets.contains(t),
+        })
+        .collect();
+
+    HttpResponse::Ok().json(tilesets)
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // Roll otel errors up to here and log them in aggregate
@@ -71,14 +795,101 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
-    HttpServer::new(|| {
+    // Make sure we can build tile URLs before we take any requests
+    if let Err(err) = TileSet::validate_all() {
+        error!("Invalid tileset configuration: {0:#}", err);
+        return Err(std::io::Error::other(err.to_string()));
+    }
+
+    let mut config = match Config::from_env() {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Couldn't load TILESETS_CONFIG: {0:#}", err);
+            return Err(std::io::Error::other(err.to_string()));
+        }
+    };
+    if let Err(err) = config.load_capabilities().await {
+        error!("Couldn't load WMTS capabilities: {0:#}", err);
+        return Err(std::io::Error::other(err.to_string()));
+    }
+    if let Err(err) = config.open_pmtiles().await {
+        error!("Couldn't open PMTiles archive: {0:#}", err);
+        return Err(std::io::Error::other(err.to_string()));
+    }
+
+    for tileset in config.missing_contact() {
+        warn!(
+            "{0}'s usage policy asks for contact details; set user_agent.contact_url or user_agent.email in TILESETS_CONFIG",
+            tileset.name()
+        );
+    }
+
+    let state = match AppState::from_env(config) {
+        Ok(state) => web::Data::new(state),
+        Err(err) => {
+            error!("Couldn't load TILE_REPLAY: {0:#}", err);
+            return Err(std::io::Error::other(err.to_string()));
+        }
+    };
+    if let Some(cassette) = &state.replay {
+        info!(
+            "Replaying {0} recorded tiles from a render of {1}",
+            cassette.interactions.len(),
+            cassette.request
+        );
+    }
+    if state.recordings_dir.is_some() && state.recording_token.is_none() {
+        warn!("TILE_RECORDINGS_DIR is set without TILE_RECORDING_TOKEN, so recording stays off");
+    }
+    info!("Default tileset chain: {0:?}", state.default_tilesets);
+
+    HttpServer::new(move || {
         App::new()
             .wrap(RequestTracing::new())
+            .app_data(state.clone())
             .route("/", web::get().to(index))
             .route("/ping", web::get().to(health))
             .service(get_image)
+            .service(get_image_meta)
+            .service(get_image_world_file)
+            .service(get_tile)
+            .service(locate)
+            .service(get_tilesets)
     })
     .bind(("0.0.0.0", 8080))?
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tile_y() {
+        assert_eq!(parse_tile_y("1449"), Some((1449, false)));
+        assert_eq!(parse_tile_y("1449.png"), Some((1449, false)));
+        assert_eq!(parse_tile_y("1449@2x.png"), Some((1449, true)));
+        assert_eq!(parse_tile_y("1449@2x"), Some((1449, true)));
+
+        for segment in [
+            "",
+            ".png",
+            "@2x.png",
+            "y.png",
+            "-1.png",
+            "1449@3x.png",
+            "14 49",
+        ] {
+            assert_eq!(parse_tile_y(segment), None, "{segment}");
+        }
+    }
+
+    #[test]
+    fn test_same_token() {
+        assert!(same_token(b"s3cret", b"s3cret"));
+        assert!(!same_token(b"s3creT", b"s3cret"));
+        assert!(!same_token(b"s3cre", b"s3cret"));
+        assert!(!same_token(b"", b"s3cret"));
+    }
+}
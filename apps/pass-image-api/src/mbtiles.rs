@@ -0,0 +1,243 @@
+// ! # mbtiles
+// !
+// ! Reads tiles from an MBTiles file, a SQLite database of tiles along with a metadata
+// ! table describing them. See https://github.com/mapbox/mbtiles-spec.
+// !
+
+use crate::coordinates::{TileId, MAX_ZOOM};
+use crate::tiles::{Tile, TileFormat};
+use actix_web::web;
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// What the metadata table tells us about the tiles in the file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MbTilesMetadata {
+    pub name: Option<String>,
+    pub format: Option<TileFormat>,
+    // [west, south, east, north] in decimal degrees
+    pub bounds: Option<[f64; 4]>,
+    pub min_zoom: Option<u32>,
+    pub max_zoom: Option<u32>,
+    pub attribution: Option<String>,
+}
+
+// An open MBTiles file. SQLite connections can't be shared between threads, so tile reads
+// take turns; they're small lookups by primary key. They still wait on the disk, so they run on
+// actix's blocking thread pool rather than holding up the worker serving requests.
+#[derive(Debug)]
+pub struct MbTiles {
+    path: PathBuf,
+    connection: Arc<Mutex<Connection>>,
+    pub metadata: MbTilesMetadata,
+}
+
+impl MbTiles {
+    // Opens the file read-only and reads its metadata
+    pub fn open(path: &Path) -> Result<MbTiles> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("opening {}", path.display()))?;
+
+        let mut statement = connection
+            .prepare("SELECT name, value FROM metadata")
+            .with_context(|| format!("reading the metadata from {}", path.display()))?;
+        let rows: HashMap<String, String> = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        drop(statement);
+
+        let metadata = MbTilesMetadata::from_rows(&rows)
+            .with_context(|| format!("reading the metadata from {}", path.display()))?;
+
+        Ok(MbTiles {
+            path: path.to_path_buf(),
+            connection: Arc::new(Mutex::new(connection)),
+            metadata,
+        })
+    }
+
+    // Reads a tile, or None if the file doesn't have it. MBTiles rows count from the bottom
+    // of the world, TMS style, so we flip y to find them.
+    pub async fn read_tile(&self, tile: TileId) -> Result<Option<Tile>> {
+        let row = (1u32 << tile.z) - 1 - tile.y;
+        let connection = Arc::clone(&self.connection);
+        let path = self.path.clone();
+
+        let data: Option<Vec<u8>> = web::block(move || {
+            let connection = connection
+                .lock()
+                .map_err(|_| anyhow!("MBTiles connection for {} poisoned", path.display()))?;
+            connection
+                .query_row(
+                    "SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                    [tile.z, tile.x, row],
+                    |row| row.get(0),
+                )
+                .optional()
+                .with_context(|| format!("reading tile {:?} from {}", tile, path.display()))
+        })
+        .await
+        .map_err(|e| anyhow!("reading tile {:?} from {}: {}", tile, self.path.display(), e))??;
+
+        let Some(data) = data else {
+            return Ok(None);
+        };
+        let format = TileFormat::detect("", &data)
+            .or(self.metadata.format)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Tile {:?} in {} isn't in a format we can read",
+                    tile,
+                    self.path.display()
+                )
+            })?;
+
+        Ok(Some(Tile {
+            format,
+            bytes: Bytes::from(data),
+        }))
+    }
+}
+
+impl MbTilesMetadata {
+    fn from_rows(rows: &HashMap<String, String>) -> Result<MbTilesMetadata> {
+        let format = match rows.get("format").map(String::as_str) {
+            None => None,
+            Some("png") => Some(TileFormat::Png),
+            Some("jpg") | Some("jpeg") => Some(TileFormat::Jpeg),
+            Some("webp") => Some(TileFormat::WebP),
+            Some(other) => return Err(anyhow!("Unsupported tile format '{}'", other)),
+        };
+
+        let bounds = match rows.get("bounds") {
+            None => None,
+            Some(bounds) => {
+                let values = bounds
+                    .split(',')
+                    .map(|v| v.trim().parse::<f64>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()
+                    .filter(|values| values.len() == 4)
+                    .ok_or_else(|| anyhow!("Invalid bounds '{}'", bounds))?;
+                Some([values[0], values[1], values[2], values[3]])
+            }
+        };
+
+        // Zoom levels past the ones we can ask for would overflow the tile rows
+        let zoom = |key: &str| -> Result<Option<u32>> {
+            rows.get(key)
+                .map(|z| {
+                    z.trim()
+                        .parse()
+                        .ok()
+                        .filter(|z| *z <= MAX_ZOOM)
+                        .ok_or_else(|| anyhow!("Invalid {} '{}'", key, z))
+                })
+                .transpose()
+        };
+        let (min_zoom, max_zoom) = (zoom("minzoom")?, zoom("maxzoom")?);
+        if let (Some(min), Some(max)) = (min_zoom, max_zoom) {
+            if min > max {
+                return Err(anyhow!("Invalid zoom range {}-{}", min, max));
+            }
+        }
+
+        Ok(MbTilesMetadata {
+            name: rows.get("name").cloned(),
+            format,
+            bounds,
+            min_zoom,
+            max_zoom,
+            attribution: rows.get("attribution").cloned(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use image::{ImageFormat, Rgba, RgbaImage};
+    use std::io::Cursor;
+
+    // Writes a small MBTiles file with a single red tile at z=1, x=1, y=0 (TMS row 1)
+    pub fn write_test_mbtiles(path: &Path) {
+        let connection = Connection::open(path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE metadata (name TEXT, value TEXT);
+                 CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+                 INSERT INTO metadata VALUES
+                    ('name', 'Test'), ('format', 'png'), ('bounds', '0,0,180,85'),
+                    ('minzoom', '1'), ('maxzoom', '3'), ('attribution', '© Test');",
+            )
+            .unwrap();
+
+        let mut png = Vec::new();
+        RgbaImage::from_pixel(256, 256, Rgba([255, 0, 0, 255]))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO tiles VALUES (1, 1, 1, ?1)",
+                rusqlite::params![png],
+            )
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_mbtiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiles.mbtiles");
+        write_test_mbtiles(&path);
+        let mbtiles = MbTiles::open(&path).expect("I can open the file");
+
+        assert_eq!(
+            mbtiles.metadata,
+            MbTilesMetadata {
+                name: Some("Test".to_string()),
+                format: Some(TileFormat::Png),
+                bounds: Some([0.0, 0.0, 180.0, 85.0]),
+                min_zoom: Some(1),
+                max_zoom: Some(3),
+                attribution: Some("© Test".to_string()),
+            }
+        );
+
+        // The tile is in TMS row 1, which is XYZ row 0
+        let tile = mbtiles
+            .read_tile(TileId { z: 1, x: 1, y: 0 })
+            .await
+            .unwrap()
+            .expect("The tile is there");
+        assert_eq!(tile.format, TileFormat::Png);
+        assert!(mbtiles
+            .read_tile(TileId { z: 1, x: 1, y: 1 })
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_metadata_rejects_bad_values() {
+        for (key, value) in [
+            ("format", "pbf"),
+            ("bounds", "1,2,3"),
+            ("minzoom", "zero"),
+            ("maxzoom", "32"),
+        ] {
+            let rows = HashMap::from([(key.to_string(), value.to_string())]);
+            assert!(MbTilesMetadata::from_rows(&rows).is_err(), "{key}");
+        }
+
+        let rows = HashMap::from([
+            ("minzoom".to_string(), "5".to_string()),
+            ("maxzoom".to_string(), "3".to_string()),
+        ]);
+        let err = MbTilesMetadata::from_rows(&rows).unwrap_err();
+        assert!(err.to_string().contains("Invalid zoom range 5-3"), "{err}");
+    }
+}
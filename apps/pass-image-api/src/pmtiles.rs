@@ -0,0 +1,784 @@
+// ! # pmtiles
+// !
+// ! Reads tiles from a PMTiles v3 archive: a single file holding a header, a directory of
+// ! tiles keyed by their position along a Hilbert curve, and the tile data itself. The
+// ! archive can be on local disk, or behind an HTTP server that supports range requests.
+// ! See https://github.com/protomaps/PMTiles/blob/main/spec/v3/spec.md.
+// !
+
+use crate::config::Config;
+use crate::coordinates::{TileId, MAX_ZOOM};
+use crate::tiles::{Tile, TileFormat, TileSet};
+use actix_web::web;
+use anyhow::{anyhow, Context, Result};
+use awc::http::header::RANGE;
+use awc::http::StatusCode;
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+const HEADER_BYTES: u64 = 127;
+
+// The spec has the header and root directory fit in the first 16 KiB of the archive
+const MAX_ROOT_BYTES: u64 = 16 * 1024 - HEADER_BYTES;
+
+// Far more than any tile, leaf directory or metadata we'd want, so that a corrupt length
+// can't have us allocate or download the world
+const MAX_READ_BYTES: u64 = 16 * 1024 * 1024;
+const MAX_DECOMPRESSED_BYTES: u64 = 64 * 1024 * 1024;
+
+// The root directory plus leaf directories nested this deep is plenty for any archive the
+// spec allows; anything deeper is corrupt
+const MAX_DIRECTORY_DEPTH: usize = 4;
+
+// Where to read an archive from
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PmTilesSource {
+    File(PathBuf),
+    // Served by something that understands Range headers, e.g. a static file server
+    Url(String),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Compression {
+    None,
+    Gzip,
+}
+
+impl Compression {
+    fn from_byte(byte: u8) -> Result<Compression> {
+        match byte {
+            // 0 is "unknown", which in practice means nothing was done to it
+            0 | 1 => Ok(Compression::None),
+            2 => Ok(Compression::Gzip),
+            3 => Err(anyhow!("Brotli compression isn't supported")),
+            4 => Err(anyhow!("Zstandard compression isn't supported")),
+            _ => Err(anyhow!("Unknown compression {}", byte)),
+        }
+    }
+
+    fn decompress(&self, bytes: Bytes) -> Result<Bytes> {
+        match self {
+            Compression::None => Ok(bytes),
+            Compression::Gzip => gunzip(&bytes, MAX_DECOMPRESSED_BYTES),
+        }
+    }
+}
+
+// Decompresses gzipped bytes, as long as they come to no more than limit bytes
+fn gunzip(bytes: &[u8], limit: u64) -> Result<Bytes> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(bytes)
+        .take(limit + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() as u64 > limit {
+        return Err(anyhow!("Decompresses to more than {} bytes", limit));
+    }
+    Ok(Bytes::from(decompressed))
+}
+
+// The fixed-size header at the start of every archive. Offsets are from the start of the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    root_offset: u64,
+    root_length: u64,
+    metadata_offset: u64,
+    metadata_length: u64,
+    leaf_offset: u64,
+    data_offset: u64,
+    internal_compression: Compression,
+    tile_compression: Compression,
+    pub format: TileFormat,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+    // [west, south, east, north] in decimal degrees
+    pub bounds: [f64; 4],
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> Result<Header> {
+        if bytes.len() < HEADER_BYTES as usize || &bytes[0..7] != b"PMTiles" {
+            return Err(anyhow!("Not a PMTiles archive"));
+        }
+        if bytes[7] != 3 {
+            return Err(anyhow!("Unsupported PMTiles version {}", bytes[7]));
+        }
+
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        let degrees_at =
+            |i: usize| i32::from_le_bytes(bytes[i..i + 4].try_into().unwrap()) as f64 / 1e7;
+
+        let root_length = u64_at(16);
+        if root_length > MAX_ROOT_BYTES {
+            return Err(anyhow!(
+                "Root directory of {} bytes, where the spec allows {}",
+                root_length,
+                MAX_ROOT_BYTES
+            ));
+        }
+
+        // Zoom levels past the ones we can ask for would overflow the tile IDs
+        let (min_zoom, max_zoom) = (bytes[100] as u32, bytes[101] as u32);
+        if min_zoom > max_zoom || max_zoom > MAX_ZOOM {
+            return Err(anyhow!("Invalid zoom range {}-{}", min_zoom, max_zoom));
+        }
+
+        let format = match bytes[99] {
+            2 => TileFormat::Png,
+            3 => TileFormat::Jpeg,
+            4 => TileFormat::WebP,
+            1 => return Err(anyhow!("Vector tiles aren't supported")),
+            other => return Err(anyhow!("Unsupported tile type {}", other)),
+        };
+
+        Ok(Header {
+            root_offset: u64_at(8),
+            root_length,
+            metadata_offset: u64_at(24),
+            metadata_length: u64_at(32),
+            leaf_offset: u64_at(40),
+            data_offset: u64_at(56),
+            internal_compression: Compression::from_byte(bytes[97])?,
+            tile_compression: Compression::from_byte(bytes[98])?,
+            format,
+            min_zoom,
+            max_zoom,
+            bounds: [
+                degrees_at(102),
+                degrees_at(106),
+                degrees_at(110),
+                degrees_at(114),
+            ],
+        })
+    }
+}
+
+// A directory entry: either a run of tile IDs that share the same tile data, or (with a
+// run length of 0) a pointer to the leaf directory that starts at the tile ID
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u64,
+    run_length: u64,
+}
+
+// Where an open archive's bytes come from. Local files stay open, with reads taking turns
+// seeking around them on actix's blocking thread pool.
+#[derive(Debug)]
+enum Reader {
+    File {
+        path: PathBuf,
+        file: Arc<Mutex<File>>,
+        size: u64,
+    },
+    Url(String),
+}
+
+impl Reader {
+    fn open(source: PmTilesSource) -> Result<Reader> {
+        match source {
+            PmTilesSource::File(path) => {
+                let file =
+                    File::open(&path).with_context(|| format!("opening {}", path.display()))?;
+                let size = file
+                    .metadata()
+                    .with_context(|| format!("reading the size of {}", path.display()))?
+                    .len();
+                Ok(Reader::File {
+                    path,
+                    file: Arc::new(Mutex::new(file)),
+                    size,
+                })
+            }
+            PmTilesSource::Url(url) => Ok(Reader::Url(url)),
+        }
+    }
+}
+
+// An open archive. We keep the header and root directory in memory; leaf directories are
+// read as we need them.
+#[derive(Debug)]
+pub struct PmTiles {
+    reader: Reader,
+    pub header: Header,
+    root: Vec<Entry>,
+    pub attribution: Option<String>,
+}
+
+impl PmTiles {
+    // Reads the archive's header, root directory and metadata
+    pub async fn open(source: PmTilesSource, config: &Config) -> Result<PmTiles> {
+        let reader = Reader::open(source)?;
+        let header = Header::parse(&read_range(&reader, config, 0, HEADER_BYTES).await?)?;
+        let root = read_directory(
+            &reader,
+            config,
+            header.internal_compression,
+            header.root_offset,
+            header.root_length,
+        )
+        .await
+        .context("reading the root directory")?;
+
+        let metadata = read_range(
+            &reader,
+            config,
+            header.metadata_offset,
+            header.metadata_length,
+        )
+        .await?;
+        let metadata = header.internal_compression.decompress(metadata)?;
+        // The metadata is free-form JSON; all we want from it is the attribution
+        let attribution = match metadata.is_empty() {
+            true => None,
+            false => serde_json::from_slice::<serde_json::Value>(&metadata)
+                .context("reading the metadata")?
+                .get("attribution")
+                .and_then(|a| a.as_str())
+                .map(str::to_string),
+        };
+
+        Ok(PmTiles {
+            reader,
+            header,
+            root,
+            attribution,
+        })
+    }
+
+    // Reads a tile, or None if the archive doesn't have it
+    pub async fn read_tile(&self, config: &Config, tile: TileId) -> Result<Option<Tile>> {
+        let tile_id = tile_id(tile);
+        let header = &self.header;
+
+        let mut leaf;
+        let mut entries = &self.root[..];
+        for _ in 0..MAX_DIRECTORY_DEPTH {
+            let Some(entry) = find_entry(entries, tile_id) else {
+                return Ok(None);
+            };
+
+            if entry.run_length > 0 {
+                let offset = header
+                    .data_offset
+                    .checked_add(entry.offset)
+                    .ok_or_else(|| anyhow!("Tile data for {:?} is past any archive's end", tile))?;
+                let bytes = read_range(&self.reader, config, offset, entry.length).await?;
+                return Ok(Some(Tile {
+                    format: header.format,
+                    bytes: header.tile_compression.decompress(bytes)?,
+                }));
+            }
+
+            let offset = header
+                .leaf_offset
+                .checked_add(entry.offset)
+                .ok_or_else(|| {
+                    anyhow!("Leaf directory for {:?} is past any archive's end", tile)
+                })?;
+            leaf = read_directory(
+                &self.reader,
+                config,
+                header.internal_compression,
+                offset,
+                entry.length,
+            )
+            .await
+            .with_context(|| format!("reading the leaf directory for {:?}", tile))?;
+            entries = &leaf;
+        }
+
+        Err(anyhow!(
+            "Directories nested more than {} deep looking for {:?}",
+            MAX_DIRECTORY_DEPTH,
+            tile
+        ))
+    }
+}
+
+async fn read_directory(
+    reader: &Reader,
+    config: &Config,
+    compression: Compression,
+    offset: u64,
+    length: u64,
+) -> Result<Vec<Entry>> {
+    let bytes = read_range(reader, config, offset, length).await?;
+    decode_directory(&compression.decompress(bytes)?)
+}
+
+// Reads length bytes from offset in the archive
+async fn read_range(reader: &Reader, config: &Config, offset: u64, length: u64) -> Result<Bytes> {
+    if length == 0 {
+        return Ok(Bytes::new());
+    }
+    if length > MAX_READ_BYTES {
+        return Err(anyhow!(
+            "Refusing to read {} bytes at {}, more than the {} we allow",
+            length,
+            offset,
+            MAX_READ_BYTES
+        ));
+    }
+    let end = offset
+        .checked_add(length)
+        .ok_or_else(|| anyhow!("{} bytes at {} is past any archive's end", length, offset))?;
+
+    match reader {
+        Reader::File { path, file, size } => {
+            if end > *size {
+                return Err(anyhow!(
+                    "{} bytes at {} is past the end of {}, which has {}",
+                    length,
+                    offset,
+                    path.display(),
+                    size
+                ));
+            }
+
+            let file = Arc::clone(file);
+            let path = path.clone();
+            let read = move || -> Result<Vec<u8>> {
+                let mut file = file
+                    .lock()
+                    .map_err(|_| anyhow!("PMTiles file {} poisoned", path.display()))?;
+                let mut bytes = vec![0; length as usize];
+                file.seek(SeekFrom::Start(offset))
+                    .and_then(|_| file.read_exact(&mut bytes))
+                    .with_context(|| {
+                        format!(
+                            "reading {} bytes at {} from {}",
+                            length,
+                            offset,
+                            path.display()
+                        )
+                    })?;
+                Ok(bytes)
+            };
+            web::block(read)
+                .await
+                .map_err(|e| anyhow!("reading {} bytes at {}: {}", length, offset, e))?
+                .map(Bytes::from)
+        }
+        Reader::Url(url) => fetch_range(config, url, offset..end).await,
+    }
+}
+
+// Fetches part of a remote archive with a range request, sending along the same User-Agent,
+// headers and query parameters as we would with tiles
+async fn fetch_range(config: &Config, url: &str, range: Range<u64>) -> Result<Bytes> {
+    let length = range.end - range.start;
+    let tileset_config = config.tileset(TileSet::PmTiles);
+    let (url, redacted_url) = tileset_config.apply_query(url);
+    let _permit = config.upstream_permit(TileSet::PmTiles).await?;
+
+    let mut request = awc::Client::new()
+        .get(&url)
+        .insert_header(("User-Agent", config.user_agent(TileSet::PmTiles)))
+        .insert_header((RANGE, format!("bytes={}-{}", range.start, range.end - 1)));
+    for (name, value) in &tileset_config.headers {
+        request = request.insert_header((name.as_str(), value.expose()));
+    }
+
+    let mut response = request
+        .send()
+        .await
+        .map_err(|e| anyhow!("Failed to send request to {}: {}", redacted_url, e))?;
+    // A 200 would be the whole archive, which is exactly what we're trying not to download
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(anyhow!(
+            "Range request to {} failed with status: {}",
+            redacted_url,
+            response.status()
+        ));
+    }
+
+    let bytes = response
+        .body()
+        .limit(length as usize)
+        .await
+        .map_err(|e| anyhow!("Failed to read response body from {}: {}", redacted_url, e))?;
+    if bytes.len() as u64 != length {
+        return Err(anyhow!(
+            "Expected {} bytes from {}, got {}",
+            length,
+            redacted_url,
+            bytes.len()
+        ));
+    }
+    Ok(bytes)
+}
+
+// Numbers tiles by zoom level, then along a Hilbert curve within the level, so that tiles
+// close together on the map tend to be close together in the archive
+fn tile_id(tile: TileId) -> u64 {
+    // All of the tiles in the zoom levels above come first
+    let mut id = ((1u64 << (2 * tile.z)) - 1) / 3;
+
+    let n = 1u64 << tile.z;
+    let (mut x, mut y) = (tile.x as u64, tile.y as u64);
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u64;
+        let ry = (y & s > 0) as u64;
+        id += s * s * ((3 * rx) ^ ry);
+
+        // Rotate the quadrant so the curve inside it lines up
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    id
+}
+
+// Finds the entry for a tile ID: the last one starting at or before it, as long as its run
+// reaches the tile (or it points at a leaf directory)
+fn find_entry(entries: &[Entry], tile_id: u64) -> Option<Entry> {
+    let after = entries.partition_point(|e| e.tile_id <= tile_id);
+    let entry = *entries.get(after.checked_sub(1)?)?;
+    match entry.run_length == 0 || tile_id - entry.tile_id < entry.run_length {
+        true => Some(entry),
+        false => None,
+    }
+}
+
+// Directories are a count followed by columns of varints: delta-encoded tile IDs, run lengths,
+// lengths, then offsets, where an offset of 0 means "straight after the previous entry" and
+// the rest are stored plus one
+fn decode_directory(bytes: &[u8]) -> Result<Vec<Entry>> {
+    let mut bytes = bytes;
+    let count = read_varint(&mut bytes)? as usize;
+    // Every entry takes at least four bytes, so a count this big can only be corrupt (and
+    // would have us allocate the world)
+    if count > bytes.len() {
+        return Err(anyhow!("Directory claims {} entries", count));
+    }
+
+    let mut entries = vec![
+        Entry {
+            tile_id: 0,
+            offset: 0,
+            length: 0,
+            run_length: 0,
+        };
+        count
+    ];
+
+    let mut tile_id = 0u64;
+    for entry in entries.iter_mut() {
+        tile_id = tile_id
+            .checked_add(read_varint(&mut bytes)?)
+            .ok_or_else(|| anyhow!("Directory has tile IDs past the last there can be"))?;
+        entry.tile_id = tile_id;
+    }
+    for entry in entries.iter_mut() {
+        entry.run_length = read_varint(&mut bytes)?;
+    }
+    for entry in entries.iter_mut() {
+        entry.length = read_varint(&mut bytes)?;
+    }
+    for i in 0..count {
+        let offset = read_varint(&mut bytes)?;
+        entries[i].offset = match (offset, i) {
+            (0, i) if i > 0 => entries[i - 1]
+                .offset
+                .checked_add(entries[i - 1].length)
+                .ok_or_else(|| anyhow!("Directory has offsets past any archive's end"))?,
+            (0, _) => return Err(anyhow!("The first directory entry has no offset")),
+            (offset, _) => offset - 1,
+        };
+    }
+
+    Ok(entries)
+}
+
+// Reads an unsigned LEB128 varint off the front of the bytes
+fn read_varint(bytes: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes
+            .split_first()
+            .ok_or_else(|| anyhow!("Directory ends in the middle of a number"))?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow!("Directory has a number that's too long"))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use image::{ImageFormat, Rgba, RgbaImage};
+    use std::io::{Cursor, Write};
+    use std::path::Path;
+
+    fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn encode_directory(entries: &[Entry]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_varint(&mut out, entries.len() as u64);
+        let mut last_id = 0;
+        for entry in entries {
+            write_varint(&mut out, entry.tile_id - last_id);
+            last_id = entry.tile_id;
+        }
+        for entry in entries {
+            write_varint(&mut out, entry.run_length);
+        }
+        for entry in entries {
+            write_varint(&mut out, entry.length);
+        }
+        for (i, entry) in entries.iter().enumerate() {
+            match i {
+                i if i > 0 && entry.offset == entries[i - 1].offset + entries[i - 1].length => {
+                    write_varint(&mut out, 0)
+                }
+                _ => write_varint(&mut out, entry.offset + 1),
+            }
+        }
+        out
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    // Writes a small archive with gzipped directories and a single red PNG, found through a
+    // leaf directory at z=1, x=1, y=0 and again at the first two z=2 tiles on the curve
+    pub fn write_test_pmtiles(path: &Path) {
+        let mut png = Vec::new();
+        RgbaImage::from_pixel(256, 256, Rgba([255, 0, 0, 255]))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let tile_length = png.len() as u64;
+
+        let leaf = gzip(&encode_directory(&[
+            Entry {
+                tile_id: 4,
+                offset: 0,
+                length: tile_length,
+                run_length: 1,
+            },
+            Entry {
+                tile_id: 5,
+                offset: 0,
+                length: tile_length,
+                run_length: 2,
+            },
+        ]));
+        let root = gzip(&encode_directory(&[Entry {
+            tile_id: 0,
+            offset: 0,
+            length: leaf.len() as u64,
+            run_length: 0,
+        }]));
+        let metadata = gzip(r#"{"name": "Test", "attribution": "© Test"}"#.as_bytes());
+
+        let root_offset = HEADER_BYTES;
+        let metadata_offset = root_offset + root.len() as u64;
+        let leaf_offset = metadata_offset + metadata.len() as u64;
+        let data_offset = leaf_offset + leaf.len() as u64;
+
+        let mut header = b"PMTiles\x03".to_vec();
+        for value in [
+            root_offset,
+            root.len() as u64,
+            metadata_offset,
+            metadata.len() as u64,
+            leaf_offset,
+            leaf.len() as u64,
+            data_offset,
+            tile_length,
+            3, // addressed tiles
+            2, // tile entries
+            1, // tile contents
+        ] {
+            header.extend(value.to_le_bytes());
+        }
+        // Clustered, gzipped directories, uncompressed PNG tiles, z=1 to z=2
+        header.extend([1, 2, 1, 2, 1, 2]);
+        for degrees in [0.0, 0.0, 180.0, 85.0] {
+            header.extend(((degrees * 1e7) as i32).to_le_bytes());
+        }
+        // Center zoom, longitude and latitude
+        header.push(1);
+        header.extend([0; 8]);
+        assert_eq!(header.len() as u64, HEADER_BYTES);
+
+        std::fs::write(path, [header, root, metadata, leaf, png].concat()).unwrap();
+    }
+
+    #[test]
+    fn test_tile_id() {
+        for (z, x, y, id) in [
+            (0, 0, 0, 0),
+            (1, 0, 0, 1),
+            (1, 0, 1, 2),
+            (1, 1, 1, 3),
+            (1, 1, 0, 4),
+            (2, 0, 0, 5),
+            (2, 3, 0, 20),
+            (3, 0, 0, 21),
+        ] {
+            assert_eq!(tile_id(TileId { z, x, y }), id, "{z}/{x}/{y}");
+        }
+    }
+
+    #[test]
+    fn test_decode_directory() {
+        let entries = [
+            Entry {
+                tile_id: 3,
+                offset: 0,
+                length: 300,
+                run_length: 1,
+            },
+            // Straight after the first, so its offset is stored as 0
+            Entry {
+                tile_id: 4,
+                offset: 300,
+                length: 20,
+                run_length: 5,
+            },
+            // A leaf directory
+            Entry {
+                tile_id: 1000,
+                offset: 9000,
+                length: 123,
+                run_length: 0,
+            },
+        ];
+        assert_eq!(
+            decode_directory(&encode_directory(&entries)).unwrap(),
+            entries
+        );
+
+        assert_eq!(find_entry(&entries, 2), None);
+        assert_eq!(find_entry(&entries, 3), Some(entries[0]));
+        assert_eq!(find_entry(&entries, 8), Some(entries[1]));
+        assert_eq!(find_entry(&entries, 9), None);
+        assert_eq!(find_entry(&entries, 5000), Some(entries[2]));
+
+        // Truncated directories, and ones claiming more entries than they have
+        let encoded = encode_directory(&entries);
+        assert!(decode_directory(&encoded[..encoded.len() - 1]).is_err());
+        assert!(decode_directory(&[0xff, 0xff, 0x03]).is_err());
+
+        // Tile IDs and offsets that would overflow
+        let mut overflowing_ids = Vec::new();
+        for value in [2, u64::MAX, 1, 1, 1, 1, 1, 1, 1] {
+            write_varint(&mut overflowing_ids, value);
+        }
+        assert!(decode_directory(&overflowing_ids).is_err());
+        let mut overflowing_offsets = Vec::new();
+        for value in [2, 1, 1, 1, 1, 10, 10, u64::MAX, 0] {
+            write_varint(&mut overflowing_offsets, value);
+        }
+        assert!(decode_directory(&overflowing_offsets).is_err());
+    }
+
+    #[test]
+    fn test_gunzip_limit() {
+        let zeros = gzip(&[0; 1000]);
+        assert_eq!(gunzip(&zeros, 1000).unwrap().len(), 1000);
+        assert!(gunzip(&zeros, 999).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_pmtiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiles.pmtiles");
+        write_test_pmtiles(&path);
+        let config = Config::default();
+        let archive = PmTiles::open(PmTilesSource::File(path.clone()), &config)
+            .await
+            .expect("I can open the archive");
+
+        assert_eq!(archive.header.format, TileFormat::Png);
+        assert_eq!((archive.header.min_zoom, archive.header.max_zoom), (1, 2));
+        assert_eq!(archive.header.bounds, [0.0, 0.0, 180.0, 85.0]);
+        assert_eq!(archive.attribution.as_deref(), Some("© Test"));
+
+        // Through the leaf directory, including the run of repeated tiles
+        for tile in [
+            TileId { z: 1, x: 1, y: 0 },
+            TileId { z: 2, x: 0, y: 0 },
+            TileId { z: 2, x: 1, y: 0 },
+        ] {
+            let found = archive
+                .read_tile(&config, tile)
+                .await
+                .unwrap()
+                .unwrap_or_else(|| panic!("{tile:?} is there"));
+            assert_eq!(found.format, TileFormat::Png);
+            assert!(image::load_from_memory(&found.bytes).is_ok());
+        }
+
+        for tile in [TileId { z: 1, x: 0, y: 0 }, TileId { z: 2, x: 1, y: 1 }] {
+            assert!(archive.read_tile(&config, tile).await.unwrap().is_none());
+        }
+
+        // Ranges past the end of the file, or of any file
+        let size = std::fs::metadata(&path).unwrap().len();
+        for (offset, length) in [(0, size + 1), (u64::MAX - 1, 10), (0, MAX_READ_BYTES + 1)] {
+            assert!(
+                read_range(&archive.reader, &config, offset, length)
+                    .await
+                    .is_err(),
+                "{offset} {length}"
+            );
+        }
+
+        // Not an archive we can read
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[7] = 2;
+        let old = dir.path().join("old.pmtiles");
+        std::fs::write(&old, &bytes).unwrap();
+        assert!(PmTiles::open(PmTilesSource::File(old), &config)
+            .await
+            .is_err());
+
+        // Nor is one whose root directory is bigger than the spec allows
+        bytes[7] = 3;
+        bytes[16..24].copy_from_slice(&(MAX_ROOT_BYTES + 1).to_le_bytes());
+        assert!(Header::parse(&bytes).is_err());
+
+        // ... or whose zoom levels go deeper than we can ask for, or are back to front
+        bytes[16..24].copy_from_slice(&1u64.to_le_bytes());
+        assert!(Header::parse(&bytes).is_ok());
+        for (min_zoom, max_zoom) in [(1, 32), (3, 2)] {
+            bytes[100] = min_zoom;
+            bytes[101] = max_zoom;
+            let err = Header::parse(&bytes).unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains(&format!("Invalid zoom range {min_zoom}-{max_zoom}")),
+                "{err}"
+            );
+        }
+    }
+}
@@ -0,0 +1,307 @@
+// ! # recording
+// !
+// ! Records every upstream tile response a render uses into a file, VCR style, and replays
+// ! renders from those files. When a render comes out wrong, recording it captures exactly the
+// ! tiles it was made from, so that we can reproduce it offline long after the tile servers
+// ! have moved on.
+// !
+
+use crate::config::Config;
+use crate::coordinates::TileId;
+use crate::tiles::{
+    fetch_upstream, is_local, tile_from_interaction, tile_url, SourceLayer, Tile, TileSet,
+    TileSource,
+};
+use anyhow::{anyhow, Context as _, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use opentelemetry::Context;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+// One tile request and the response we got to it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Interaction {
+    // The URL with any configured query parameters redacted, so recordings never hold API keys
+    pub url: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    // Base64 encoded, since tiles are binary
+    #[serde(
+        serialize_with = "serialize_body",
+        deserialize_with = "deserialize_body"
+    )]
+    pub body: Bytes,
+}
+
+impl Interaction {
+    // The value of the first header with the name, ignoring case as HTTP does
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn serialize_body<S: Serializer>(body: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(body))
+}
+
+fn deserialize_body<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    STANDARD
+        .decode(encoded)
+        .map(Bytes::from)
+        .map_err(serde::de::Error::custom)
+}
+
+// A recording of one render: the request that asked for it and the tile responses it used,
+// in the order they arrived
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Cassette {
+    pub request: String,
+    pub interactions: Vec<Interaction>,
+}
+
+impl Cassette {
+    pub fn load(path: &Path) -> Result<Cassette> {
+        let json = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_slice(&json).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self)?;
+        fs::write(path, json).with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+// How many recordings there are in a directory of them
+pub fn count_recordings(dir: &Path) -> Result<usize> {
+    let mut count = 0;
+    for entry in fs::read_dir(dir).with_context(|| format!("listing {}", dir.display()))? {
+        if entry?.path().extension().is_some_and(|ext| ext == "json") {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// Fetches tiles as the config would, noting down each upstream response as it goes. Tiles
+// we draw or read from local files aren't recorded; they're still there to replay from.
+pub struct Recorder<'a> {
+    config: &'a Config,
+    interactions: Mutex<Vec<Interaction>>,
+}
+
+impl<'a> Recorder<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Recorder {
+            config,
+            interactions: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Finishes the recording, for the request we rendered
+    pub fn into_cassette(self, request: &str) -> Cassette {
+        Cassette {
+            request: request.to_string(),
+            interactions: self.interactions.into_inner().unwrap(),
+        }
+    }
+}
+
+impl TileSource for Recorder<'_> {
+    fn config(&self) -> &Config {
+        self.config
+    }
+
+    async fn fetch(
+        &self,
+        tileset: TileSet,
+        tile: TileId,
+        retina: bool,
+        source: Option<&SourceLayer>,
+        time: Option<&str>,
+        cx: Context,
+    ) -> Result<Option<Tile>> {
+        if is_local(self.config, tileset) {
+            return self
+                .config
+                .fetch(tileset, tile, retina, source, time, cx)
+                .await;
+        }
+
+        let (url, redacted_url) = tile_url(self.config, tileset, tile, retina, source, time)?;
+        let interaction = fetch_upstream(self.config, tileset, &url, &redacted_url, cx).await?;
+        self.interactions.lock().unwrap().push(interaction.clone());
+        tile_from_interaction(self.config, tileset, source, &interaction)
+    }
+}
+
+// Serves tiles from a recording instead of the tile servers, checking them just as if they'd
+// come from upstream. Tiles that weren't recorded fail as a network error would, so the
+// replay falls through tileset chains the same way the original render did.
+pub struct Replay<'a> {
+    config: &'a Config,
+    interactions: HashMap<&'a str, &'a Interaction>,
+}
+
+impl<'a> Replay<'a> {
+    pub fn new(config: &'a Config, cassette: &'a Cassette) -> Self {
+        Replay {
+            config,
+            interactions: cassette
+                .interactions
+                .iter()
+                .map(|i| (i.url.as_str(), i))
+                .collect(),
+        }
+    }
+}
+
+impl TileSource for Replay<'_> {
+    fn config(&self) -> &Config {
+        self.config
+    }
+
+    async fn fetch(
+        &self,
+        tileset: TileSet,
+        tile: TileId,
+        retina: bool,
+        source: Option<&SourceLayer>,
+        time: Option<&str>,
+        cx: Context,
+    ) -> Result<Option<Tile>> {
+        if is_local(self.config, tileset) {
+            return self
+                .config
+                .fetch(tileset, tile, retina, source, time, cx)
+                .await;
+        }
+
+        let (_, redacted_url) = tile_url(self.config, tileset, tile, retina, source, time)?;
+        let interaction = self
+            .interactions
+            .get(redacted_url.as_str())
+            .ok_or_else(|| anyhow!("No recorded response for {}", redacted_url))?;
+        tile_from_interaction(self.config, tileset, source, interaction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiles::tests::solid_tile;
+    use crate::tiles::TileFormat;
+
+    fn interaction(url: &str, status: u16, content_type: &str, body: Bytes) -> Interaction {
+        Interaction {
+            url: url.to_string(),
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body,
+        }
+    }
+
+    #[test]
+    fn test_cassette_round_trip() {
+        let tile = solid_tile([0, 0, 255, 255]);
+        let cassette = Cassette {
+            request: "/images/8.1/46.6/512?tileset=osm".to_string(),
+            interactions: vec![
+                interaction(
+                    "https://tile.example.com/3/4/2.png",
+                    200,
+                    "image/png",
+                    tile.bytes,
+                ),
+                interaction(
+                    "https://tile.example.com/3/4/3.png",
+                    404,
+                    "text/plain",
+                    Bytes::from("Not Found"),
+                ),
+            ],
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cassette.json");
+        cassette.save(&path).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(json["interactions"][1]["body"], "Tm90IEZvdW5k");
+
+        let loaded = Cassette::load(&path).unwrap();
+        assert_eq!(loaded.request, cassette.request);
+        assert_eq!(loaded.interactions, cassette.interactions);
+        assert_eq!(
+            loaded.interactions[0].header("content-type"),
+            Some("image/png")
+        );
+    }
+
+    #[test]
+    fn test_count_recordings() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(count_recordings(dir.path()).unwrap(), 0);
+
+        Cassette::default()
+            .save(&dir.path().join("tiles-1.json"))
+            .unwrap();
+        Cassette::default()
+            .save(&dir.path().join("tiles-2.json"))
+            .unwrap();
+        fs::write(dir.path().join("notes.txt"), "not a recording").unwrap();
+        assert_eq!(count_recordings(dir.path()).unwrap(), 2);
+
+        assert!(count_recordings(&dir.path().join("nope")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replay_tiles() {
+        let config = Config::default();
+        let tile = TileId { z: 3, x: 4, y: 2 };
+        let (_, url) = tile_url(&config, TileSet::Osm, tile, false, None, None).unwrap();
+        let below = TileId { z: 3, x: 4, y: 3 };
+        let (_, url_below) = tile_url(&config, TileSet::Osm, below, false, None, None).unwrap();
+        let cassette = Cassette {
+            request: String::new(),
+            interactions: vec![
+                interaction(&url, 200, "image/png", solid_tile([0, 0, 255, 255]).bytes),
+                interaction(&url_below, 500, "text/plain", Bytes::from("Oops")),
+            ],
+        };
+        let replay = Replay::new(&config, &cassette);
+
+        let replayed = replay
+            .fetch(TileSet::Osm, tile, false, None, None, Context::new())
+            .await
+            .unwrap()
+            .expect("The tile was recorded");
+        assert_eq!(replayed.format, TileFormat::Png);
+        assert_eq!(replayed.bytes, cassette.interactions[0].body);
+
+        // Failures replay as failures, and tiles we never fetched can't be made up
+        assert!(replay
+            .fetch(TileSet::Osm, below, false, None, None, Context::new())
+            .await
+            .is_err());
+        let elsewhere = TileId { z: 3, x: 5, y: 2 };
+        assert!(replay
+            .fetch(TileSet::Osm, elsewhere, false, None, None, Context::new())
+            .await
+            .is_err());
+
+        // Tiles we draw ourselves don't need recording
+        assert!(replay
+            .fetch(TileSet::Debug, elsewhere, false, None, None, Context::new())
+            .await
+            .unwrap()
+            .is_some());
+    }
+}
@@ -0,0 +1,281 @@
+// ! # template
+// !
+// ! Parses and fills in the URL templates tilesets use to locate their tiles, e.g.
+// ! https://{s}.tile.example.com/{z}/{x}/{y}{r}.png
+// !
+
+use crate::coordinates::TileId;
+use anyhow::{anyhow, Result};
+
+// The variables a template can use, on top of any custom ones the tileset defines
+#[derive(Debug, Clone, PartialEq)]
+enum Variable {
+    Z,
+    X,
+    Y,
+    // The y coordinate counted from the bottom, as TMS servers expect
+    FlippedY,
+    // Bing-style quadkey, e.g. "213" for z=3, x=3, y=5
+    Quadkey,
+    // One of the tileset's subdomains, picked per tile so requests spread across them
+    Subdomain,
+    // "@2x" for retina tiles, otherwise nothing
+    Retina,
+    Time,
+}
+
+impl Variable {
+    fn from_name(name: &str) -> Option<Variable> {
+        match name {
+            "z" => Some(Variable::Z),
+            "x" => Some(Variable::X),
+            "y" => Some(Variable::Y),
+            "-y" => Some(Variable::FlippedY),
+            "q" => Some(Variable::Quadkey),
+            "s" => Some(Variable::Subdomain),
+            "r" => Some(Variable::Retina),
+            "time" => Some(Variable::Time),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Variable(Variable),
+}
+
+// A parsed URL template. Custom variables are filled in when we parse it, so all that's left
+// to do per tile is the built-in ones.
+#[derive(Debug, Clone)]
+pub struct UrlTemplate {
+    parts: Vec<Part>,
+    subdomains: Vec<String>,
+    default_time: Option<String>,
+}
+
+impl UrlTemplate {
+    // Parses the template, checking that every variable in it is one we can fill in. Templates
+    // using {s} need some subdomains, and those using {time} need a default time.
+    pub fn parse(
+        template: &str,
+        subdomains: &[&str],
+        variables: &[(&str, &str)],
+        default_time: Option<&str>,
+    ) -> Result<UrlTemplate> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+
+        while let Some(open) = rest.find(['{', '}']) {
+            if rest[open..].starts_with('}') {
+                return Err(anyhow!("Unmatched '}}' in URL template {}", template));
+            }
+            let close = rest[open..]
+                .find('}')
+                .map(|c| open + c)
+                .ok_or_else(|| anyhow!("Unclosed '{{' in URL template {}", template))?;
+
+            push_literal(&mut parts, &rest[..open]);
+
+            let name = &rest[open + 1..close];
+            let variable = match Variable::from_name(name) {
+                Some(Variable::Subdomain) if subdomains.is_empty() => {
+                    return Err(anyhow!(
+                        "URL template {} uses {{s}}, but there are no subdomains",
+                        template
+                    ))
+                }
+                Some(Variable::Time) if default_time.is_none() => {
+                    return Err(anyhow!(
+                        "URL template {} uses {{time}}, but there's no default time",
+                        template
+                    ))
+                }
+                Some(variable) => variable,
+                None => match variables.iter().find(|(key, _)| *key == name) {
+                    Some((_, value)) => {
+                        push_literal(&mut parts, value);
+                        rest = &rest[close + 1..];
+                        continue;
+                    }
+                    None => {
+                        return Err(anyhow!(
+                            "Unknown variable {{{}}} in URL template {}",
+                            name,
+                            template
+                        ))
+                    }
+                },
+            };
+            parts.push(Part::Variable(variable));
+            rest = &rest[close + 1..];
+        }
+        push_literal(&mut parts, rest);
+
+        Ok(UrlTemplate {
+            parts,
+            subdomains: subdomains.iter().map(|s| s.to_string()).collect(),
+            default_time: default_time.map(str::to_string),
+        })
+    }
+
+    // Whether the name is one of the variables we fill in per tile, which custom variables
+    // can't take the place of
+    pub fn is_built_in(name: &str) -> bool {
+        Variable::from_name(name).is_some()
+    }
+
+    // Whether the template has a {time} to fill in, i.e. its layer has editions to pick from
+    pub fn uses_time(&self) -> bool {
+        self.parts.contains(&Part::Variable(Variable::Time))
+    }
+
+    // Fills in the template for a tile. time overrides the tileset's default time.
+    pub fn render(&self, tile: TileId, retina: bool, time: Option<&str>) -> String {
+        let mut url = String::new();
+
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => url.push_str(literal),
+                Part::Variable(Variable::Z) => url.push_str(&tile.z.to_string()),
+                Part::Variable(Variable::X) => url.push_str(&tile.x.to_string()),
+                Part::Variable(Variable::Y) => url.push_str(&tile.y.to_string()),
+                Part::Variable(Variable::FlippedY) => {
+                    url.push_str(&((1u32 << tile.z) - 1 - tile.y).to_string())
+                }
+                Part::Variable(Variable::Quadkey) => url.push_str(&quadkey(tile)),
+                Part::Variable(Variable::Subdomain) => {
+                    // parse only lets {s} in with subdomains to pick from, but there's no
+                    // need to divide by zero if one ever gets past it
+                    let index = (tile.x as usize + tile.y as usize) % self.subdomains.len().max(1);
+                    if let Some(subdomain) = self.subdomains.get(index) {
+                        url.push_str(subdomain);
+                    }
+                }
+                Part::Variable(Variable::Retina) => url.push_str(if retina { "@2x" } else { "" }),
+                Part::Variable(Variable::Time) => {
+                    url.push_str(time.or(self.default_time.as_deref()).unwrap_or(""))
+                }
+            }
+        }
+
+        url
+    }
+}
+
+// Adds a literal onto the end of the template, merging it with the last one if it was
+// also a literal
+fn push_literal(parts: &mut Vec<Part>, literal: &str) {
+    if literal.is_empty() {
+        return;
+    }
+    match parts.last_mut() {
+        Some(Part::Literal(last)) => last.push_str(literal),
+        _ => parts.push(Part::Literal(literal.to_string())),
+    }
+}
+
+// Interleaves the bits of x and y, most significant first, one base-4 digit per zoom level
+fn quadkey(tile: TileId) -> String {
+    (1..=tile.z)
+        .rev()
+        .map(|level| {
+            let mask = 1 << (level - 1);
+            let mut digit = 0;
+            if tile.x & mask != 0 {
+                digit += 1;
+            }
+            if tile.y & mask != 0 {
+                digit += 2;
+            }
+            char::from(b'0' + digit)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TILE: TileId = TileId { z: 3, x: 3, y: 5 };
+
+    #[test]
+    fn test_render_subdomain_without_subdomains() {
+        // parse won't make one of these, but render doesn't fall over if it's ever made
+        let template = UrlTemplate {
+            parts: vec![
+                Part::Literal("https://".to_string()),
+                Part::Variable(Variable::Subdomain),
+                Part::Literal("example.com".to_string()),
+            ],
+            subdomains: vec![],
+            default_time: None,
+        };
+        assert_eq!(template.render(TILE, false, None), "https://example.com");
+    }
+
+    #[test]
+    fn test_render_built_in_variables() {
+        let template = UrlTemplate::parse(
+            "https://{s}.example.com/{z}/{x}/{y}/{-y}/{q}{r}.png?t={time}",
+            &["a", "b", "c"],
+            &[],
+            Some("current"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            template.render(TILE, false, None),
+            "https://c.example.com/3/3/5/2/213.png?t=current"
+        );
+        assert_eq!(
+            template.render(TILE, true, Some("2019")),
+            "https://c.example.com/3/3/5/2/213@2x.png?t=2019"
+        );
+
+        // Neighbouring tiles go to different subdomains
+        assert!(template
+            .render(TileId { z: 3, x: 4, y: 5 }, false, None)
+            .starts_with("https://a."));
+    }
+
+    #[test]
+    fn test_render_custom_variables() {
+        let template = UrlTemplate::parse(
+            "https://tiles.example.com/{layer}/{z}/{x}/{y}.{ext}",
+            &[],
+            &[("layer", "hiking"), ("ext", "png")],
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            template.render(TILE, false, None),
+            "https://tiles.example.com/hiking/3/3/5.png"
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_templates() {
+        for template in [
+            "https://example.com/{z}/{x}/{y",
+            "https://example.com/{z}/{x}/y}",
+            "https://example.com/{zoom}/{x}/{y}",
+            "https://{s}.example.com/{z}/{x}/{y}",
+            "https://example.com/{time}/{z}/{x}/{y}",
+        ] {
+            assert!(
+                UrlTemplate::parse(template, &[], &[], None).is_err(),
+                "{template}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_quadkey() {
+        assert_eq!(quadkey(TileId { z: 0, x: 0, y: 0 }), "");
+        assert_eq!(quadkey(TileId { z: 1, x: 1, y: 1 }), "3");
+        assert_eq!(quadkey(TILE), "213");
+    }
+}
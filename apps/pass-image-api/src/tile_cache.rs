@@ -0,0 +1,160 @@
+// ! # tile_cache
+// !
+// ! Keeps the tiles we've recently fetched from tile servers in memory, so that renders and
+// ! tile requests close together don't ask upstream for the same tiles over and over. It
+// ! holds up to a fixed number of bytes of tiles, forgetting the least recently used first.
+// !
+
+use crate::coordinates::TileId;
+use crate::tiles::{SourceLayer, Tile, TileSet};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+// What tells one upstream tile from another: where it's from, which layer and edition, and
+// where it is. Retina tiles are different tiles.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TileKey {
+    pub tileset: TileSet,
+    pub source: Option<SourceLayer>,
+    pub time: Option<String>,
+    pub tile: TileId,
+    pub retina: bool,
+}
+
+// A cached fetch: the tile, or None if the tileset told us it has no tile there
+#[derive(Debug)]
+struct Entry {
+    tile: Option<Tile>,
+    // When the entry was last used, as a count of uses across the whole cache
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    tiles: HashMap<TileKey, Entry>,
+    // The keys by when they were last used, oldest first
+    by_use: BTreeMap<u64, TileKey>,
+    uses: u64,
+    bytes: usize,
+}
+
+#[derive(Debug)]
+pub struct TileCache {
+    max_bytes: usize,
+    entries: Mutex<Entries>,
+}
+
+impl TileCache {
+    pub fn new(max_bytes: usize) -> Self {
+        TileCache {
+            max_bytes,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    // The tile we cached for the key, if there is one. The outer Option is whether we had it
+    // cached at all.
+    pub fn get(&self, key: &TileKey) -> Option<Option<Tile>> {
+        let mut entries = self.entries.lock().ok()?;
+        entries.uses += 1;
+        let now = entries.uses;
+
+        let entry = entries.tiles.get_mut(key)?;
+        let last_used = std::mem::replace(&mut entry.used, now);
+        let tile = entry.tile.clone();
+        entries.by_use.remove(&last_used);
+        entries.by_use.insert(now, key.clone());
+        Some(tile)
+    }
+
+    // Remembers a fetched tile, making room for it by forgetting the least recently used ones.
+    // Tiles bigger than the whole cache aren't kept.
+    pub fn insert(&self, key: TileKey, tile: Option<Tile>) {
+        let size = tile_bytes(&tile);
+        if size > self.max_bytes {
+            return;
+        }
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        entries.uses += 1;
+        let now = entries.uses;
+
+        if let Some(old) = entries.tiles.remove(&key) {
+            entries.by_use.remove(&old.used);
+            entries.bytes -= tile_bytes(&old.tile);
+        }
+        while entries.bytes + size > self.max_bytes {
+            let Some((_, oldest)) = entries.by_use.pop_first() else {
+                break;
+            };
+            if let Some(evicted) = entries.tiles.remove(&oldest) {
+                entries.bytes -= tile_bytes(&evicted.tile);
+            }
+        }
+
+        entries.bytes += size;
+        entries.by_use.insert(now, key.clone());
+        entries.tiles.insert(key, Entry { tile, used: now });
+    }
+}
+
+fn tile_bytes(tile: &Option<Tile>) -> usize {
+    tile.as_ref().map_or(0, |t| t.bytes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiles::TileFormat;
+    use bytes::Bytes;
+
+    fn key(x: u32) -> TileKey {
+        TileKey {
+            tileset: TileSet::Osm,
+            source: None,
+            time: None,
+            tile: TileId { z: 3, x, y: 2 },
+            retina: false,
+        }
+    }
+
+    fn tile(bytes: usize) -> Option<Tile> {
+        Some(Tile {
+            format: TileFormat::Png,
+            bytes: Bytes::from(vec![0; bytes]),
+        })
+    }
+
+    #[test]
+    fn test_tile_cache() {
+        let cache = TileCache::new(300);
+        assert!(cache.get(&key(0)).is_none());
+
+        cache.insert(key(0), tile(100));
+        cache.insert(key(1), tile(100));
+        // Known missing tiles are worth remembering too
+        cache.insert(key(2), None);
+        assert_eq!(cache.get(&key(0)).unwrap().unwrap().bytes.len(), 100);
+        assert!(cache.get(&key(2)).unwrap().is_none());
+
+        // Making room forgets the tile used longest ago, which 0 no longer is
+        cache.insert(key(3), tile(150));
+        assert!(cache.get(&key(1)).is_none());
+        assert!(cache.get(&key(0)).is_some());
+        assert!(cache.get(&key(3)).is_some());
+
+        // Layers, editions and retina tiles are all different tiles
+        let mut other = key(0);
+        other.time = Some("19901231".to_string());
+        assert!(cache.get(&other).is_none());
+        other = key(0);
+        other.retina = true;
+        assert!(cache.get(&other).is_none());
+
+        // Tiles too big for the whole cache don't push everything else out
+        cache.insert(key(4), tile(301));
+        assert!(cache.get(&key(4)).is_none());
+        assert!(cache.get(&key(0)).is_some());
+    }
+}
@@ -2,101 +2,1057 @@
 // ! Provides functions for retrieving and mosaicing
 // tile imagery from public tile imagery sources.
 
-use crate::coordinates::{
-    lat_long_and_image_size_to_bounding_box, lat_long_to_tile_coords, ConstrainedTileBox, LatLong,
-    TileCoordinate,
-};
+use crate::blend::{self, BlendMode};
+use crate::config::{Config, TileSetConfig};
+use crate::coordinates::{ConstrainedTileBox, TileBox, TileId, WorldPixel, MAX_ZOOM, TILE_SIZE_PX};
+use crate::coverage::{Coverage, WEB_MERCATOR_BBOX};
+use crate::debug_tiles::{render_tile, Pattern};
+use crate::encoding::{encode, OutputFormat};
+use crate::georef::ImageMetadata;
+use crate::recording::Interaction;
+use crate::template::UrlTemplate;
+use crate::tile_cache::TileKey;
 
-use opentelemetry_instrumentation_actix_web::ClientExt;
-use anyhow::Result;
+use anyhow::{Context as _, Result};
 use awc::http::header::CONTENT_TYPE;
 use awc::http::StatusCode;
 use bytes::Bytes;
+use futures::future;
 use futures::stream::{self, StreamExt};
-use image::{DynamicImage, GenericImage, ImageBuffer};
-use log::debug;
+use image::imageops::{self, FilterType};
+use image::{GenericImage, ImageBuffer, ImageFormat, RgbaImage};
+use log::{debug, warn};
 use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer};
-use opentelemetry::{global, Context};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_instrumentation_actix_web::ClientExt;
 use std::borrow::Borrow;
-use std::collections::{HashMap, HashSet};
-use std::io::Cursor;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+
+// The image formats we can mosaic tiles from
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TileFormat {
+    Png,
+    Jpeg,
+    WebP,
+}
+
+impl TileFormat {
+    // Parses a format name as used in config, e.g. "png"
+    pub fn from_name(name: &str) -> Option<TileFormat> {
+        match name {
+            "png" => Some(TileFormat::Png),
+            "jpeg" | "jpg" => Some(TileFormat::Jpeg),
+            "webp" => Some(TileFormat::WebP),
+            _ => None,
+        }
+    }
+
+    // The file extension tile servers use for the format, for {ext} in URL templates
+    fn extension(&self) -> &'static str {
+        match self {
+            TileFormat::Png => "png",
+            TileFormat::Jpeg => "jpeg",
+            TileFormat::WebP => "webp",
+        }
+    }
+
+    // The media type to serve tiles in the format with
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            TileFormat::Png => "image/png",
+            TileFormat::Jpeg => "image/jpeg",
+            TileFormat::WebP => "image/webp",
+        }
+    }
+
+    // Parses a Content-Type header value, ignoring any parameters (e.g. "; charset=...")
+    pub fn from_content_type(content_type: &str) -> Option<TileFormat> {
+        let essence = content_type.split(';').next().unwrap_or("").trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "image/png" => Some(TileFormat::Png),
+            "image/jpeg" | "image/jpg" => Some(TileFormat::Jpeg),
+            "image/webp" => Some(TileFormat::WebP),
+            _ => None,
+        }
+    }
+
+    // Works out the format from the magic bytes at the start of the tile
+    fn sniff(bytes: &[u8]) -> Option<TileFormat> {
+        match image::guess_format(bytes).ok()? {
+            ImageFormat::Png => Some(TileFormat::Png),
+            ImageFormat::Jpeg => Some(TileFormat::Jpeg),
+            ImageFormat::WebP => Some(TileFormat::WebP),
+            _ => None,
+        }
+    }
+
+    // Works out what format a tile is in. We trust the content type if it names a format we know,
+    // and otherwise fall back to sniffing, as plenty of servers send application/octet-stream
+    // or no content type at all.
+    pub fn detect(content_type: &str, bytes: &[u8]) -> Option<TileFormat> {
+        TileFormat::from_content_type(content_type).or_else(|| TileFormat::sniff(bytes))
+    }
+
+    fn image_format(&self) -> ImageFormat {
+        match self {
+            TileFormat::Png => ImageFormat::Png,
+            TileFormat::Jpeg => ImageFormat::Jpeg,
+            TileFormat::WebP => ImageFormat::WebP,
+        }
+    }
+}
+
+// One of the layers a WMTS-style tileset publishes, e.g. swisstopo's aerial imagery or its
+// hiking trails. Each has its own identifier in the tile URLs, tile format and zoom range.
+// Layers read from a GetCapabilities document also bring their own URL template and the
+// times they have editions for.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SourceLayer {
+    pub id: String,
+    pub format: TileFormat,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+    // Replaces the tileset's URL pattern when set
+    pub template: Option<String>,
+    // The layer's default time first, if it has a time dimension of its own
+    pub times: Vec<String>,
+}
+
+impl SourceLayer {
+    pub fn new(id: &str, format: TileFormat, min_zoom: u32, max_zoom: u32) -> SourceLayer {
+        SourceLayer {
+            id: id.to_string(),
+            format,
+            min_zoom,
+            max_zoom,
+            template: None,
+            times: Vec::new(),
+        }
+    }
+}
+
+// One layer of a render: a chain of tilesets to take its tiles from, and how to lay it over
+// the layers beneath it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    pub tilesets: Vec<TileSet>,
+    // From 0 (invisible) to 1 (opaque)
+    pub opacity: f32,
+    pub mode: BlendMode,
+    // Which edition to ask tilesets with a time dimension for, rather than their default
+    pub time: Option<String>,
+    // Which layer to draw from the WMTS-style tileset in the chain. None means its
+    // built-in default.
+    pub source: Option<SourceLayer>,
+}
+
+impl Layer {
+    // A plain, fully opaque layer
+    pub fn opaque(tilesets: Vec<TileSet>) -> Layer {
+        Layer {
+            tilesets,
+            opacity: 1.0,
+            mode: BlendMode::Normal,
+            time: None,
+            source: None,
+        }
+    }
+
+    // Parses a layer of the form tileset[/layer][:opacity[:mode]], e.g.
+    // "swisstopo/hiking:0.5:multiply". Source layers are looked up in the config.
+    pub fn parse(spec: &str, config: &Config) -> Result<Layer> {
+        let mut parts = spec.trim().split(':');
+
+        let first = parts.next().unwrap_or("");
+        let (name, source) = match first.split_once('/') {
+            Some((name, source)) => (name, Some(source)),
+            None => (first, None),
+        };
+        let tileset = TileSet::from_name(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown tileset '{}'", name))?;
+
+        let opacity = match parts.next() {
+            None => 1.0,
+            Some(opacity) => opacity
+                .parse()
+                .ok()
+                .filter(|o| (0.0..=1.0).contains(o))
+                .ok_or_else(|| anyhow::anyhow!("Invalid opacity '{}', expected 0 to 1", opacity))?,
+        };
+
+        let mode = match parts.next() {
+            None => BlendMode::Normal,
+            Some(mode) => BlendMode::from_name(mode).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unknown blend mode '{}', expected normal, multiply or screen",
+                    mode
+                )
+            })?,
+        };
+
+        if parts.next().is_some() {
+            return Err(anyhow::anyhow!(
+                "Invalid layer '{}', expected tileset[/layer][:opacity[:mode]]",
+                spec
+            ));
+        }
+
+        let mut layer = Layer {
+            tilesets: vec![tileset],
+            opacity,
+            mode,
+            time: None,
+            source: None,
+        };
+        layer.select_source(source, config)?;
+        Ok(layer)
+    }
 
-#[derive(Copy, Clone)]
+    // Parses a comma-separated list of layers, bottom first
+    pub fn parse_list(specs: &str, config: &Config) -> Result<Vec<Layer>> {
+        specs
+            .split(',')
+            .map(|spec| Layer::parse(spec, config))
+            .collect()
+    }
+
+    // Picks the layer to draw from the WMTS-style tileset in the chain by name, or its default
+    // if name is None. Chains without one can't have a layer picked.
+    pub fn select_source(&mut self, name: Option<&str>, config: &Config) -> Result<()> {
+        let Some(tileset) = self.tilesets.iter().find(|t| t.supports_layers()) else {
+            return match name {
+                Some(name) => Err(anyhow::anyhow!(
+                    "Can't pick layer '{}', none of the tilesets have layers to pick from",
+                    name
+                )),
+                None => Ok(()),
+            };
+        };
+
+        let name = name.unwrap_or(tileset.default_layer());
+        let source = config.source_layer(*tileset, name).ok_or_else(|| {
+            anyhow::anyhow!(
+                "{} has no layer '{}', expected one of {}",
+                tileset.name(),
+                name,
+                config.source_layer_names(*tileset).join(", ")
+            )
+        })?;
+        self.source = Some(source);
+        Ok(())
+    }
+
+    // Asks the layer for an older edition of the map, e.g. "1990", from the first tileset in its
+    // chain whose source layer has a time dimension, i.e. a {time} in its URL template. Layers
+    // read from capabilities take one of the times they list. Otherwise it's the tileset's
+    // default time, or one of the years in the config, which switches the tileset's default
+    // layer to its time series. Returns false if nothing in the chain has a time dimension.
+    pub fn select_time(&mut self, time: &str, config: &Config) -> Result<bool> {
+        let Some(tileset) = self.tilesets.iter().copied().find(|t| {
+            config
+                .url_template(*t, self.source_for(*t))
+                .is_ok_and(|template| template.uses_time())
+        }) else {
+            return Ok(false);
+        };
+        let source = self.source_for(tileset).cloned();
+
+        if let Some(source) = source.as_ref().filter(|s| !s.times.is_empty()) {
+            if !source.times.iter().any(|t| t == time) {
+                return Err(anyhow::anyhow!(
+                    "{} layer {} has no edition for time '{}', expected one of {}",
+                    tileset.name(),
+                    source.id,
+                    time,
+                    source.times.join(", ")
+                ));
+            }
+        } else if Some(time) != tileset.default_time() {
+            let value = config.time_value(tileset, time).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "{} has no edition for time '{}', expected one of {}",
+                    tileset.name(),
+                    time,
+                    config.available_times(tileset).join(", ")
+                )
+            })?;
+            let series = tileset
+                .time_series_source()
+                .filter(|_| source == tileset.default_source())
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "{} layer {} only has its current edition",
+                        tileset.name(),
+                        source.as_ref().map_or("", |s| s.id.as_str())
+                    )
+                })?;
+            self.source = Some(series);
+            self.time = Some(value.to_string());
+            return Ok(true);
+        }
+
+        self.time = Some(time.to_string());
+        Ok(true)
+    }
+
+    // The source layer to draw from the tileset, if it's a WMTS-style tileset
+    pub fn source_for(&self, tileset: TileSet) -> Option<&SourceLayer> {
+        self.source.as_ref().filter(|_| tileset.supports_layers())
+    }
+
+    // The deepest zoom level the tileset serves tiles for in this layer
+    pub fn max_zoom(&self, tileset: TileSet, config: &Config) -> u32 {
+        match self.source_for(tileset) {
+            Some(source) => source.max_zoom,
+            None => config.max_zoom(tileset),
+        }
+    }
+
+    // Whether we should bother asking the tileset for a tile in this layer
+    fn serves(&self, tileset: TileSet, tile: TileId, config: &Config) -> bool {
+        match self.source_for(tileset) {
+            Some(source) => {
+                (source.min_zoom..=source.max_zoom).contains(&tile.z)
+                    && config.coverage(tileset).intersects(tile.bbox_wgs84())
+            }
+            None => config.serves(tileset, tile),
+        }
+    }
+}
+
+// Returned by fetch_image when the area we're asked for falls entirely outside the
+// coverage of every tileset in the chain
+#[derive(Debug)]
+pub struct OutsideCoverage {
+    pub bbox_wgs84: [f64; 4],
+    pub tilesets: Vec<TileSet>,
+}
+
+impl fmt::Display for OutsideCoverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self.tilesets.iter().map(|t| t.name()).collect();
+        write!(
+            f,
+            "The requested area {0:?} is entirely outside the coverage of {1}",
+            self.bbox_wgs84,
+            names.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for OutsideCoverage {}
+
+// A tile fetched from a TileSet, along with the format it was delivered in
+#[derive(Debug, Clone)]
+pub struct Tile {
+    pub format: TileFormat,
+    pub bytes: Bytes,
+}
+
+impl Tile {
+    fn decode(&self) -> Result<RgbaImage> {
+        let image = image::load_from_memory_with_format(&self.bytes, self.format.image_format())?;
+        Ok(image.to_rgba8())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum TileSet {
     Osm,
     Swisstopo,
+    // Whichever WMS server the config points us at
+    Wms,
+    // A local MBTiles file, also from the config
+    MbTiles,
+    // A PMTiles archive, on disk or behind an HTTP range server
+    PmTiles,
+    // A local directory of {z}/{x}/{y} tiles
+    Directory,
+    // Tiles we draw ourselves, to check renders against
+    Debug,
 }
 
 impl TileSet {
+    pub const ALL: [TileSet; 7] = [
+        TileSet::Osm,
+        TileSet::Swisstopo,
+        TileSet::Wms,
+        TileSet::MbTiles,
+        TileSet::PmTiles,
+        TileSet::Directory,
+        TileSet::Debug,
+    ];
+
+    pub fn from_name(name: &str) -> Option<TileSet> {
+        match name {
+            "osm" => Some(TileSet::Osm),
+            "swisstopo" => Some(TileSet::Swisstopo),
+            "wms" => Some(TileSet::Wms),
+            "mbtiles" => Some(TileSet::MbTiles),
+            "pmtiles" => Some(TileSet::PmTiles),
+            "directory" => Some(TileSet::Directory),
+            "debug" => Some(TileSet::Debug),
+            _ => None,
+        }
+    }
+
+    // Parses a comma-separated chain of tileset names like "swisstopo,osm", skipping any we
+    // don't know
+    pub fn parse_chain(names: &str) -> Vec<TileSet> {
+        names
+            .split(',')
+            .filter_map(|name| TileSet::from_name(name.trim()))
+            .collect()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TileSet::Osm => "osm",
+            TileSet::Swisstopo => "swisstopo",
+            TileSet::Wms => "wms",
+            TileSet::MbTiles => "mbtiles",
+            TileSet::PmTiles => "pmtiles",
+            TileSet::Directory => "directory",
+            TileSet::Debug => "debug",
+        }
+    }
+
+    // The attribution the tileset's terms of use require us to display alongside its imagery
+    pub fn attribution(&self) -> &'static str {
+        match self {
+            TileSet::Osm => "© OpenStreetMap contributors",
+            TileSet::Swisstopo => "© swisstopo",
+            // Up to whoever runs the server or made the file, so it comes from the config
+            TileSet::Wms | TileSet::MbTiles | TileSet::PmTiles | TileSet::Directory => "",
+            TileSet::Debug => "",
+        }
+    }
+
+    // Whether the tileset's usage policy asks us to say who we are and how to reach us. See
+    // https://operations.osmfoundation.org/policies/tiles/ for OSM's.
+    pub fn requires_contact(&self) -> bool {
+        match self {
+            TileSet::Osm => true,
+            TileSet::Swisstopo
+            | TileSet::Wms
+            | TileSet::MbTiles
+            | TileSet::PmTiles
+            | TileSet::Directory
+            | TileSet::Debug => false,
+        }
+    }
+
+    // How many requests we send the tileset's server at once. OSM's tile usage policy asks for
+    // no more than two; other servers get a few more, but not a whole render's worth at once.
+    pub fn max_concurrent_requests(&self) -> usize {
+        match self {
+            TileSet::Osm => 2,
+            TileSet::Swisstopo
+            | TileSet::Wms
+            | TileSet::MbTiles
+            | TileSet::PmTiles
+            | TileSet::Directory
+            | TileSet::Debug => 8,
+        }
+    }
+
+    // The tile formats we'll accept from this tileset
+    fn formats(&self) -> &'static [TileFormat] {
+        match self {
+            TileSet::Osm | TileSet::Debug => &[TileFormat::Png],
+            TileSet::Swisstopo => &[TileFormat::Png, TileFormat::Jpeg],
+            TileSet::Wms | TileSet::MbTiles | TileSet::PmTiles | TileSet::Directory => {
+                &[TileFormat::Png, TileFormat::Jpeg, TileFormat::WebP]
+            }
+        }
+    }
+
+    // The edge length of the tiles this tileset serves, in pixels
+    pub fn tile_size(&self) -> u32 {
+        match self {
+            TileSet::Osm
+            | TileSet::Swisstopo
+            | TileSet::Wms
+            | TileSet::MbTiles
+            | TileSet::PmTiles
+            | TileSet::Directory
+            | TileSet::Debug => TILE_SIZE_PX,
+        }
+    }
+
+    // The deepest zoom level the tileset serves tiles for, unless the config knows better;
+    // see Config::max_zoom
+    pub fn max_zoom(&self) -> u32 {
+        match self {
+            TileSet::Osm => 19,
+            TileSet::Swisstopo => 18,
+            // WMS servers (and we) render at whatever scale we ask for
+            TileSet::Wms | TileSet::Debug => MAX_ZOOM,
+            // Up to what the files tell us
+            TileSet::MbTiles | TileSet::PmTiles | TileSet::Directory => MAX_ZOOM,
+        }
+    }
+
+    // The area the tileset has imagery for, unless the config knows better; see
+    // Config::coverage
+    pub fn coverage(&self) -> Coverage {
+        match self {
+            TileSet::Osm
+            | TileSet::Wms
+            | TileSet::MbTiles
+            | TileSet::PmTiles
+            | TileSet::Directory
+            | TileSet::Debug => Coverage::Bbox(WEB_MERCATOR_BBOX),
+            // A rough outline of Switzerland, padded out a little so that tiles along the
+            // border still come from swisstopo
+            TileSet::Swisstopo => Coverage::Polygon(vec![
+                [5.90, 46.15],
+                [6.05, 46.50],
+                [6.40, 46.95],
+                [6.80, 47.20],
+                [6.80, 47.55],
+                [7.60, 47.65],
+                [8.60, 47.85],
+                [9.65, 47.65],
+                [9.65, 47.10],
+                [10.55, 47.05],
+                [10.55, 46.50],
+                [10.20, 46.15],
+                [9.05, 45.78],
+                [8.70, 46.05],
+                [7.85, 45.88],
+                [7.05, 45.80],
+                [6.75, 46.10],
+                [6.80, 46.45],
+                [6.30, 46.25],
+                [6.10, 46.10],
+            ]),
+        }
+    }
+
+    // Tilesets with an {r} in their URL pattern can serve "retina" tiles at twice
+    // the usual size by substituting in "@2x". WMS servers render any size we like, as do we.
+    pub fn supports_retina(&self) -> bool {
+        matches!(self, TileSet::Wms | TileSet::Debug) || self.url_pattern().contains("{r}")
+    }
+
+    // Tilesets with a {layer} in their URL pattern publish several layers to pick from. The
+    // debug tileset's layers are the patterns it draws.
+    pub fn supports_layers(&self) -> bool {
+        *self == TileSet::Debug || self.url_pattern().contains("{layer}")
+    }
+
+    // The layers we know the tileset publishes without any config, by the names we use
+    // for them. The first is the one we draw by default.
+    pub fn built_in_layers(&self) -> Vec<(&'static str, SourceLayer)> {
+        match self {
+            TileSet::Osm
+            | TileSet::Wms
+            | TileSet::MbTiles
+            | TileSet::PmTiles
+            | TileSet::Directory => vec![],
+            TileSet::Swisstopo => vec![
+                (
+                    "colour",
+                    SourceLayer::new("ch.swisstopo.landeskarte-farbe-10", TileFormat::Png, 0, 18),
+                ),
+                (
+                    "grey",
+                    SourceLayer::new("ch.swisstopo.landeskarte-grau-10", TileFormat::Png, 0, 18),
+                ),
+                (
+                    "swissimage",
+                    SourceLayer::new("ch.swisstopo.swissimage", TileFormat::Jpeg, 0, 20),
+                ),
+                (
+                    "hiking",
+                    SourceLayer::new("ch.swisstopo.swisstlm3d-wanderwege", TileFormat::Png, 0, 18),
+                ),
+            ],
+            TileSet::Debug => vec![
+                (
+                    "checkerboard",
+                    SourceLayer::new("checkerboard", TileFormat::Png, 0, MAX_ZOOM),
+                ),
+                (
+                    "gradient",
+                    SourceLayer::new("gradient", TileFormat::Png, 0, MAX_ZOOM),
+                ),
+            ],
+        }
+    }
+
+    // The name of the layer we draw when the request doesn't pick one
+    pub fn default_layer(&self) -> &'static str {
+        self.built_in_layers()
+            .first()
+            .map(|(name, _)| *name)
+            .unwrap_or("")
+    }
+
+    // Tilesets with a {time} in their URL pattern have older editions we can ask for
+    pub fn supports_time(&self) -> bool {
+        self.url_pattern().contains("{time}")
+    }
+
+    // Whether the tileset's tiles come from a URL template, which the config can replace
+    pub fn fetches_from_urls(&self) -> bool {
+        !self.url_pattern().is_empty()
+    }
+
+    // The size of the tiles we'll mosaic when rendering at the given scale. Retina tiles
+    // double up on pixels at the same zoom; otherwise we work with the normal tiles and
+    // let the zoom selection find the resolution we need.
+    pub fn tile_size_for_scale(&self, scale: u32) -> u32 {
+        if scale >= 2 && self.supports_retina() {
+            self.tile_size() * 2
+        } else {
+            self.tile_size()
+        }
+    }
+
+    // The template for the tileset's tile URLs; see UrlTemplate for the variables it can use.
+    // WMS tiles come from GetMap requests instead, local tiles from their files, and debug
+    // tiles from debug_tiles.
     fn url_pattern(&self) -> &str {
         match self {
             TileSet::Osm => "https://tile.openstreetmap.org/{z}/{x}/{y}.png",
-            TileSet::Swisstopo => "https://wmts.geo.admin.ch/1.0.0/ch.swisstopo.landeskarte-farbe-10/default/current/3857/{z}/{x}/{y}.png"
+            TileSet::Wms
+            | TileSet::MbTiles
+            | TileSet::PmTiles
+            | TileSet::Directory
+            | TileSet::Debug => "",
+            TileSet::Swisstopo => {
+                "https://wmts.geo.admin.ch/1.0.0/{layer}/default/{time}/3857/{z}/{x}/{y}.{ext}"
+            }
+        }
+    }
+
+    // What {time} is in the URL template when the request doesn't ask for anything else
+    pub fn default_time(&self) -> Option<&'static str> {
+        match self {
+            TileSet::Osm
+            | TileSet::Wms
+            | TileSet::MbTiles
+            | TileSet::PmTiles
+            | TileSet::Directory
+            | TileSet::Debug => None,
+            TileSet::Swisstopo => Some("current"),
         }
     }
+
+    // The template for the tileset's tile URLs, with {layer} and {ext} filled in from the
+    // source layer, or the default one if it's None, and the config's variables after them.
+    // Source layers with their own template use it instead of the tileset's, and a template
+    // in the config replaces the built-in one.
+    pub fn url_template(
+        &self,
+        source: Option<&SourceLayer>,
+        config: &TileSetConfig,
+    ) -> Result<UrlTemplate> {
+        let default = self.default_source();
+        let source = source.or(default.as_ref());
+        let mut variables: Vec<(&str, &str)> = match source {
+            Some(source) => vec![("layer", &source.id), ("ext", source.format.extension())],
+            None => vec![],
+        };
+        variables.extend(
+            config
+                .variables
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+        let pattern = source
+            .and_then(|s| s.template.as_deref())
+            .or(config.url_template.as_deref())
+            .unwrap_or(self.url_pattern());
+        let subdomains: Vec<&str> = config.subdomains.iter().map(String::as_str).collect();
+        let default_time = source
+            .and_then(|s| s.times.first())
+            .map(String::as_str)
+            .or(self.default_time());
+
+        UrlTemplate::parse(pattern, &subdomains, &variables, default_time)
+            .with_context(|| format!("parsing the URL template for {}", self.name()))
+    }
+
+    // The layer we draw from when none is picked, for tilesets that have layers
+    pub fn default_source(&self) -> Option<SourceLayer> {
+        self.built_in_layers().into_iter().next().map(|(_, l)| l)
+    }
+
+    // The layer with the tileset's older editions, which we switch to from its default layer
+    // when asked for one of them. swisstopo's everyday maps only publish the current edition;
+    // its time series has the national map as it was at the end of each year it was updated.
+    pub fn time_series_source(&self) -> Option<SourceLayer> {
+        match self {
+            TileSet::Swisstopo => Some(SourceLayer::new(
+                "ch.swisstopo.zeitreihen",
+                TileFormat::Png,
+                0,
+                18,
+            )),
+            TileSet::Osm
+            | TileSet::Wms
+            | TileSet::MbTiles
+            | TileSet::PmTiles
+            | TileSet::Directory
+            | TileSet::Debug => None,
+        }
+    }
+
+    // Checks that we can build tile URLs for all of the tilesets and their layers, so that a
+    // bad built-in template stops us at startup rather than failing requests later
+    pub fn validate_all() -> Result<()> {
+        let config = TileSetConfig::default();
+        for tileset in TileSet::ALL {
+            tileset.url_template(None, &config)?;
+            for (_, source) in tileset.built_in_layers() {
+                tileset.url_template(Some(&source), &config)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Where fetch_tile_box gets its tiles from. Outside of tests that's the config, which knows
+// where each tileset's tiles live; tests swap in fixtures so that renders don't need the network.
+pub trait TileSource {
+    // The config describing the tilesets, which decides which of them we ask for which tiles
+    fn config(&self) -> &Config;
+
+    // Fetches a single tile, as fetch_tile does
+    async fn fetch(
+        &self,
+        tileset: TileSet,
+        tile: TileId,
+        retina: bool,
+        source: Option<&SourceLayer>,
+        time: Option<&str>,
+        cx: Context,
+    ) -> Result<Option<Tile>>;
 }
 
-// Fetches a single tile from a given TileSet
-// Fetches a single tile from a given TileSet
-async fn fetch_tile(t: TileSet, x: u32, y: u32, z: u32, cx: Context) -> Result<Bytes> {
-    // Format the URL for the requested tile (zoom, x, y)
-    let url = t
-        .url_pattern()
-        .replace("{z}", &z.to_string())
-        .replace("{x}", &x.to_string())
-        .replace("{y}", &y.to_string());
+impl TileSource for Config {
+    fn config(&self) -> &Config {
+        self
+    }
+
+    async fn fetch(
+        &self,
+        tileset: TileSet,
+        tile: TileId,
+        retina: bool,
+        source: Option<&SourceLayer>,
+        time: Option<&str>,
+        cx: Context,
+    ) -> Result<Option<Tile>> {
+        // Local tiles are as quick to read again as they'd be to find in the cache
+        if is_local(self, tileset) {
+            return fetch_tile(self, tileset, tile, retina, source, time, cx).await;
+        }
+
+        let key = TileKey {
+            tileset,
+            source: source.cloned(),
+            time: time.map(str::to_string),
+            tile,
+            retina,
+        };
+        if let Some(cached) = self.tile_cache.get(&key) {
+            return Ok(cached);
+        }
+        let fetched = fetch_tile(self, tileset, tile, retina, source, time, cx).await?;
+        self.tile_cache.insert(key, fetched.clone());
+        Ok(fetched)
+    }
+}
 
+// Fetches a single tile from a given TileSet, optionally at retina resolution, from one of its
+// source layers and from an older edition, sending along any headers and query parameters
+// configured for the tileset. Returns None when the tileset knows it has no tile there, as
+// local files can.
+async fn fetch_tile(
+    config: &Config,
+    t: TileSet,
+    tile: TileId,
+    retina: bool,
+    source: Option<&SourceLayer>,
+    time: Option<&str>,
+    cx: Context,
+) -> Result<Option<Tile>> {
+    let tileset_config = config.tileset(t);
+
+    if t == TileSet::Debug {
+        let size = if retina {
+            t.tile_size() * 2
+        } else {
+            t.tile_size()
+        };
+        let name = source.map(|s| s.id.as_str()).unwrap_or(t.default_layer());
+        let pattern = Pattern::from_name(name)
+            .ok_or_else(|| anyhow::anyhow!("There's no debug pattern called '{}'", name))?;
+        return render_tile(tile, size, pattern).map(Some);
+    }
+    if let Some(mbtiles) = &tileset_config.mbtiles {
+        return mbtiles.read_tile(tile).await;
+    }
+    if let Some(pmtiles) = &tileset_config.pmtiles {
+        return pmtiles.read_tile(config, tile).await;
+    }
+    if let Some(directory) = &tileset_config.directory {
+        return directory.read_tile(tile).await;
+    }
+
+    let (url, redacted_url) = tile_url(config, t, tile, retina, source, time)?;
+    let interaction = fetch_upstream(config, t, &url, &redacted_url, cx).await?;
+    tile_from_interaction(config, t, source, &interaction)
+}
+
+// Whether fetch_tile finds the tileset's tiles without going upstream: drawn by us or read
+// from local files (or a PMTiles archive, which may be remote but isn't a tile server)
+pub fn is_local(config: &Config, t: TileSet) -> bool {
+    let tileset_config = config.tileset(t);
+    t == TileSet::Debug
+        || tileset_config.mbtiles.is_some()
+        || tileset_config.pmtiles.is_some()
+        || tileset_config.directory.is_some()
+}
+
+// Formats the URL for the requested tile (zoom, x, y), along with a copy to log. The configured
+// query parameters may well be API keys, so the copy has them redacted.
+pub fn tile_url(
+    config: &Config,
+    t: TileSet,
+    tile: TileId,
+    retina: bool,
+    source: Option<&SourceLayer>,
+    time: Option<&str>,
+) -> Result<(String, String)> {
+    let tileset_config = config.tileset(t);
+    let tile_url = match &tileset_config.wms {
+        Some(wms) if t == TileSet::Wms => {
+            let size = if retina {
+                t.tile_size() * 2
+            } else {
+                t.tile_size()
+            };
+            wms.get_map_url(tile, size)
+        }
+        _ => config.url_template(t, source)?.render(tile, retina, time),
+    };
+    Ok(tileset_config.apply_query(&tile_url))
+}
+
+// Makes the HTTP GET request for a tile and reads the whole response, whatever its status
+pub async fn fetch_upstream(
+    config: &Config,
+    t: TileSet,
+    url: &str,
+    redacted_url: &str,
+    cx: Context,
+) -> Result<Interaction> {
+    let tileset_config = config.tileset(t);
     let client = awc::Client::new();
+    let _permit = config.upstream_permit(t).await?;
 
     // Make an HTTP GET request to fetch the tile
-    let mut response = client
-        .get(&url)
-        .insert_header(("User-Agent", "dd-sdlc-demo"))
-        .trace_request_with_context(cx.clone())
-        .send()
+    let mut request = client
+        .get(url)
+        .insert_header(("User-Agent", config.user_agent(t)));
+    if let Some(from) = config.contact_email(t) {
+        request = request.insert_header(("From", from));
+    }
+    for (name, value) in &tileset_config.headers {
+        request = request.insert_header((name.as_str(), value.expose()));
+    }
+
+    // The instrumented client records the full URL on its span, so when there are secrets in
+    // the query string we trace the request ourselves instead
+    let sent = if tileset_config.query.is_empty() {
+        request.trace_request_with_context(cx.clone()).send().await
+    } else {
+        send_with_redacted_span(request, redacted_url, &cx).await
+    };
+    let url = redacted_url;
+    let mut response =
+        sent.map_err(|e| anyhow::anyhow!("Failed to send request to {}: {}", url, e))?;
+
+    // Header values aren't always UTF-8; recordings keep them as near as they can
+    let headers = response
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect();
+
+    // Extract the body as bytes
+    let body = response
+        .body()
         .await
-        .map_err(|e| anyhow::anyhow!("Failed to send request to {}: {}", url, e))?;
+        .map_err(|e| anyhow::anyhow!("Failed to read response body from {}: {}", url, e))?;
+
+    Ok(Interaction {
+        url: url.to_string(),
+        status: response.status().as_u16(),
+        headers,
+        body,
+    })
+}
+
+// Turns a tile server's response into a tile, checking that it succeeded and sent an image
+// the tileset (or its layer) is meant to serve
+pub fn tile_from_interaction(
+    config: &Config,
+    t: TileSet,
+    source: Option<&SourceLayer>,
+    interaction: &Interaction,
+) -> Result<Option<Tile>> {
+    let url = &interaction.url;
 
     // Check if the response status is a success
-    if response.status() != StatusCode::OK {
+    if interaction.status != StatusCode::OK.as_u16() {
         return Err(anyhow::anyhow!(
             "Request to {} failed with status: {}",
             url,
-            response.status()
+            interaction.status
         ));
     }
 
-    // Check the content type
-    let content_type = response
-        .headers()
-        .get(CONTENT_TYPE)
-        .and_then(|val| val.to_str().ok())
-        .unwrap_or("")
-        .to_string();
+    let content_type = interaction.header(CONTENT_TYPE.as_str()).unwrap_or("");
 
-    if content_type != "image/png" {
-        return Err(anyhow::anyhow!(
+    // Check we got a format we can decode, and that the tileset (or its layer) is meant to
+    // serve it
+    let wms_format = match &config.tileset(t).wms {
+        Some(wms) => Some(wms.tile_format()?),
+        None => None,
+    };
+    let expected = match (source, &wms_format) {
+        (Some(source), _) => std::slice::from_ref(&source.format),
+        (None, Some(format)) => std::slice::from_ref(format),
+        (None, None) => t.formats(),
+    };
+    match TileFormat::detect(content_type, &interaction.body) {
+        Some(format) if expected.contains(&format) => Ok(Some(Tile {
+            format,
+            bytes: interaction.body.clone(),
+        })),
+        _ => Err(anyhow::anyhow!(
             "Unexpected content type from {}: {}",
             url,
             content_type
-        ));
+        )),
     }
+}
 
-    // Extract and return the body as bytes
-    response
-        .body()
-        .await
-        .map_err(|e| anyhow::anyhow!("Failed to read response body from {}: {}", url, e))
+// Sends the request inside a client span that only knows about the redacted URL, passing the
+// span along to the tile server as the instrumented client would. It resolves to whatever
+// sending the request does, so that both ways of sending it give the same response.
+async fn send_with_redacted_span(
+    request: awc::ClientRequest,
+    redacted_url: &str,
+    cx: &Context,
+) -> <awc::SendClientRequest as Future>::Output {
+    let tracer = global::tracer("fetch_image_tracer");
+    let span = tracer
+        .span_builder("GET")
+        .with_kind(SpanKind::Client)
+        .with_attributes([
+            KeyValue::new("http.request.method", "GET"),
+            KeyValue::new("url.full", redacted_url.to_string()),
+        ])
+        .start_with_context(&tracer, cx);
+    let cx = cx.with_span(span);
+
+    let result = inject_trace_context(request, &cx).send().await;
+    match &result {
+        Ok(response) => cx.span().set_attribute(KeyValue::new(
+            "http.response.status_code",
+            response.status().as_u16() as i64,
+        )),
+        Err(e) => cx.span().set_status(Status::Error {
+            description: e.to_string().into(),
+        }),
+    }
+    cx.span().end();
+
+    result
+}
+
+// Adds the trace context headers (traceparent and friends) for the span in cx to the request
+fn inject_trace_context(mut request: awc::ClientRequest, cx: &Context) -> awc::ClientRequest {
+    let mut headers: HashMap<String, String> = HashMap::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(cx, &mut headers));
+    for (name, value) in headers {
+        request = request.insert_header((name, value));
+    }
+    request
+}
+
+// Fetches a tile from the first tileset in the layer's chain that serves it, falling through
+// to the next one whenever a fetch fails or the tileset turns out not to have the tile. Returns
+// the tileset the tile came from along with it, or None if none of the tilesets have it.
+// Tilesets without a time dimension ignore the layer's time.
+async fn fetch_tile_from_chain(
+    tiles: &impl TileSource,
+    layer: &Layer,
+    tile: TileId,
+    tile_size: u32,
+    cx: Context,
+) -> Result<Option<(TileSet, Tile)>> {
+    let config = tiles.config();
+    let mut last_error = None;
+    let time = layer.time.as_deref();
+
+    for tileset in layer
+        .tilesets
+        .iter()
+        .filter(|t| layer.serves(**t, tile, config))
+    {
+        // Only ask for retina tiles from tilesets that have them; the rest get scaled up
+        let retina = tile_size > tileset.tile_size() && tileset.supports_retina();
+        let source = layer.source_for(*tileset);
+        match tiles
+            .fetch(*tileset, tile, retina, source, time, cx.clone())
+            .await
+        {
+            Ok(Some(fetched)) => return Ok(Some((*tileset, fetched))),
+            Ok(None) => {}
+            Err(e) => {
+                warn!(
+                    "Couldn't fetch tile {0:?} from {1}, trying the next tileset: {2}",
+                    tile,
+                    tileset.name(),
+                    e
+                );
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match last_error {
+        Some(e) => Err(e),
+        None => Ok(None),
+    }
 }
 
-// Fetches all of the tiles within a TileBox
+// Fetches all of the tiles within a TileBox for a layer, each from the first tileset in its
+// chain that can supply it. Tiles none of the tilesets have are left out; mosaic_image decides
+// whether that's a problem.
 // Note - we assume that a TileBox is only 2D - e.g., all tiles
 // are within the same zoom level.
 async fn fetch_tile_box(
-    tileset: TileSet,
-    top_left: &TileCoordinate,
-    bottom_right: &TileCoordinate,
-) -> Result<HashMap<(u32, u32, u32), Bytes>> {
+    tiles: &impl TileSource,
+    layer: &Layer,
+    tile_box: &TileBox,
+    tile_size: u32,
+) -> Result<HashMap<TileId, (TileSet, Tile)>> {
     // Create a manual span for this function
     // This span will be the parent of all outgoing calls
     let tracer = global::tracer("fetch_image_tracer");
@@ -109,23 +1065,17 @@ async fn fetch_tile_box(
     let ctx = cx.borrow();
 
     // Collect all tile coordinates in the bounding box
-    let mut tile_coords = Vec::new();
-
-    for x in top_left.x.floor() as u32..=bottom_right.x.ceil() as u32 {
-        for y in top_left.y.floor() as u32..=bottom_right.y.ceil() as u32 {
-            tile_coords.push((x, y, top_left.z));
-        }
-    }
+    let tile_ids = tile_box.tile_ids();
 
     // Fetch all tiles in parallel, but fail if any tile fetch fails
     let mut tile_map = HashMap::new();
 
-    let tile_fetches = stream::iter(tile_coords.into_iter().map(|tile| {
+    let tile_fetches = stream::iter(tile_ids.into_iter().map(|tile| {
         // For each tile, fetch the corresponding tile asynchronously
         async move {
-            fetch_tile(tileset, tile.0, tile.1, tile.2, ctx.clone())
+            fetch_tile_from_chain(tiles, layer, tile, tile_size, ctx.clone())
                 .await
-                .map(|bytes| (tile, bytes))
+                .map(|fetched| (tile, fetched))
         }
     }))
     .buffer_unordered(10) // Limit to 10 concurrent requests
@@ -135,9 +1085,10 @@ async fn fetch_tile_box(
     // Check for any errors in the results
     for tile_result in tile_fetches {
         match tile_result {
-            Ok((tile, bytes)) => {
-                tile_map.insert((tile.0, tile.1, tile.2), bytes); // Insert the successful result into the map
+            Ok((tile_id, Some(tile))) => {
+                tile_map.insert(tile_id, tile); // Insert the successful result into the map
             }
+            Ok((_, None)) => {}
             Err(e) => {
                 // If any tile fetch fails, set the span status to Error and return the error
                 cx.span().set_status(Status::Error {
@@ -155,44 +1106,124 @@ async fn fetch_tile_box(
     Ok(tile_map)
 }
 
-// Fetches an image centered at the given point, using the provided TileSet.
-pub async fn fetch_image_from_point(
-    center: LatLong,
-    radius_km: f32,
-    image_size: u32,
-    tileset: TileSet,
-) -> Result<Bytes> {
-    // Find the center
-    let tile_box = lat_long_and_image_size_to_bounding_box(center, radius_km, image_size);
-
-    // Fetch the image
-    fetch_image(tileset, &tile_box).await
+// Fetches one tile, as is, from the first tileset in the layer's chain that serves it, for the
+// tile proxy. Returns the tileset it came from along with it, or None if none of them have it.
+pub async fn fetch_single_tile(
+    tiles: &impl TileSource,
+    layer: &Layer,
+    tile: TileId,
+    tile_size: u32,
+) -> Result<Option<(TileSet, Tile)>> {
+    let tracer = global::tracer("fetch_image_tracer");
+    let span = tracer
+        .span_builder("fetch_tile")
+        .with_kind(SpanKind::Internal)
+        .start(&tracer);
+    let cx = Context::current_with_span(span);
+
+    let result = fetch_tile_from_chain(tiles, layer, tile, tile_size, cx.clone()).await;
+    match &result {
+        Ok(_) => cx.span().set_status(Status::Ok),
+        Err(e) => cx.span().set_status(Status::Error {
+            description: e.to_string().into(),
+        }),
+    }
+    cx.span().end();
+
+    result
+}
+
+// An encoded image, along with the tilesets that supplied its tiles, bottom layer first
+pub struct RenderedImage {
+    pub bytes: Bytes,
+    pub tilesets: Vec<TileSet>,
+}
+
+// Fetches an image at the given point by compositing the given layers (bottom first), with
+// tiles from the TileSource, over the provided ConstrainedTileBox, encoded in the given format.
+// Use lat_long_and_image_size_to_bounding_box with the bottom layer's first tileset's
+// tile_size_for_scale to find the ConstrainedTileBox for a point.
+pub async fn fetch_image(
+    tiles: &impl TileSource,
+    layers: &[Layer],
+    tile_box: &ConstrainedTileBox,
+    format: OutputFormat,
+    quality: u8,
+) -> Result<RenderedImage> {
+    let config = tiles.config();
+
+    // Don't bother fetching anything if none of the tilesets have imagery here
+    let bbox_wgs84 = tile_box.bbox_wgs84();
+    let tilesets: Vec<TileSet> = layers.iter().flat_map(|l| l.tilesets.clone()).collect();
+    if !tilesets
+        .iter()
+        .any(|t| config.coverage(*t).intersects(bbox_wgs84))
+    {
+        return Err(OutsideCoverage {
+            bbox_wgs84,
+            tilesets,
+        }
+        .into());
+    }
+
+    let (image, used) = mosaic_image(tiles, layers, tile_box).await?;
+
+    let meta = ImageMetadata::for_render(tile_box, &used, config);
+    let encoded = encode(image, format, quality, &meta)?;
+
+    // Return the image as Bytes
+    Ok(RenderedImage {
+        bytes: Bytes::from(encoded),
+        tilesets: used,
+    })
 }
 
-// Fetches an image at the given point using the provided TileSet and ConstrainedTileBox
+// Mosaics the tiles covering the ConstrainedTileBox for each layer, composites the layers into a
+// single image, and reports which of the tilesets were used.
 // This function will fetch enough tiles around the given point to allow it to crop the resulting
 // image down to ensure we have enough pixels to cover the requested resolution.
-async fn fetch_image(tileset: TileSet, tile_box: &ConstrainedTileBox) -> Result<Bytes> {
-    // Fetch all tiles in the bounding box
-    let tiles = fetch_tile_box(
-        tileset,
-        &tile_box.tile_box.top_left,
-        &tile_box.tile_box.bottom_right,
+async fn mosaic_image(
+    tiles: &impl TileSource,
+    layers: &[Layer],
+    tile_box: &ConstrainedTileBox,
+) -> Result<(RgbaImage, Vec<TileSet>)> {
+    let tile_size = tile_box.tile_size_px;
+
+    // Fetch all tiles in the bounding box, for all of the layers at once
+    let layer_tiles = future::try_join_all(
+        layers
+            .iter()
+            .map(|layer| fetch_tile_box(tiles, layer, &tile_box.tile_box, tile_size)),
     )
     .await?;
 
-    // Each tile is 256x256 pixels
-    let tile_size = 256;
+    // Overlays can have holes, e.g. hiking trails only where there are trails, and those are
+    // left transparent. The base map has to be whole, though, as it was before we had layers.
+    if let Some(missing) = tile_box
+        .tile_box
+        .tile_ids()
+        .into_iter()
+        .find(|id| !layer_tiles[0].contains_key(id))
+    {
+        let names: Vec<&str> = layers[0].tilesets.iter().map(|t| t.name()).collect();
+        return Err(anyhow::anyhow!(
+            "None of {} serve tile {:?} of the base layer",
+            names.join(","),
+            missing
+        ));
+    }
 
-    // Calculate the total number of tiles in x and y directions
-    let unique_x: HashSet<u32> = tiles.keys().map(|(x, _, _)| *x).collect::<HashSet<u32>>();
-    let num_tiles_x = unique_x.len() as u32;
-    let unique_y: HashSet<u32> = tiles.keys().map(|(_, y, _)| *y).collect::<HashSet<u32>>();
-    let num_tiles_y = unique_y.len() as u32;
+    let mut used: Vec<TileSet> = Vec::new();
+    for (layer, tiles) in layers.iter().zip(&layer_tiles) {
+        for tileset in &layer.tilesets {
+            if !used.contains(tileset) && tiles.values().any(|(source, _)| source == tileset) {
+                used.push(*tileset);
+            }
+        }
+    }
 
     // Create a new empty image with dimensions for all tiles
-    let img_width = num_tiles_x * tile_size;
-    let img_height = num_tiles_y * tile_size;
+    let (img_width, img_height) = tile_box.outer_size_px();
 
     let meter = global::meter("processing_time_meter");
     let processing_time = meter.f64_histogram("processing_time").build();
@@ -200,43 +1231,28 @@ async fn fetch_image(tileset: TileSet, tile_box: &ConstrainedTileBox) -> Result<
 
     let mut full_image = ImageBuffer::new(img_width, img_height);
 
-    // Draw each tile into the final image
-    for (tile_coord, tile_bytes) in tiles {
-        let tile_img = image::load_from_memory(&tile_bytes).expect("I can load my tiles");
-
-        let x_offset = (tile_coord.0 - tile_box.tile_box.top_left.x.floor() as u32) * tile_size;
-        let y_offset = (tile_coord.1 - tile_box.tile_box.top_left.y.floor() as u32) * tile_size;
+    // The world pixel at the top-left of our mosaic
+    let mosaic_origin = tile_box
+        .tile_box
+        .outer_top_left()
+        .top_left()
+        .to_world_pixel(tile_size);
 
-        full_image
-            .copy_from(&tile_img.to_rgba8(), x_offset, y_offset)
-            .unwrap();
+    // Draw each layer and lay it over the ones beneath it
+    for (layer, tiles) in layers.iter().zip(layer_tiles) {
+        let layer_image = draw_tiles(tiles, tile_size, &mosaic_origin, (img_width, img_height))?;
+        blend::composite(&mut full_image, &layer_image, layer.opacity, layer.mode);
     }
 
-    // What's the full size of our output image?
-    let full_image_width =
-        (tile_box.tile_box.bottom_right.x - tile_box.tile_box.top_left.x) * 256.0;
-    let full_image_height =
-        (tile_box.tile_box.bottom_right.y - tile_box.tile_box.top_left.y) * 256.0;
-    debug!(
-        "Full image size: {}x{}",
-        full_image_width, full_image_height
-    );
+    debug!("Full image size: {}x{}", img_width, img_height);
+
+    // Work out the offsets from the left and top of the mosaic. The image origin is offset in
+    // by half the targeted radius from the center, so we can then use the full radius as the
+    // width and height, and we end up centered where we should be centered
+    let offset = tile_box.image_origin().to_image_pixel(&mosaic_origin);
+    let offset_left = offset.x as u32;
+    let offset_top = offset.y as u32;
 
-    // Work out the offsets from the left and top of the image, so that we can
-    let center_pos_abs =
-        lat_long_to_tile_coords(&tile_box.center, tile_box.tile_box.bottom_right.z);
-    let center_x_tile_offset = center_pos_abs.x - tile_box.tile_box.outer_top_left().0 as f32;
-    let center_y_tile_offset = center_pos_abs.y - tile_box.tile_box.outer_top_left().1 as f32;
-    let center_x_px = (center_x_tile_offset * 256.0) as u32;
-    let center_y_px = (center_y_tile_offset * 256.0) as u32;
-
-    // Offset in by half the targeted radius, in pixels
-    // We can then use the full radius as the width and height, and we end up centered where we should
-    // be centered
-    let offset_left = center_x_px - (tile_box.inner_size_px.0 / 2);
-    let offset_top = center_y_px - (tile_box.inner_size_px.1 / 2);
-
-    debug!("Center: {0}, {1}", center_x_px, center_y_px);
     debug!("Offset: {0}, {1}", offset_left, offset_top);
     debug!(
         "W/h   : {0}, {1}",
@@ -244,47 +1260,582 @@ async fn fetch_image(tileset: TileSet, tile_box: &ConstrainedTileBox) -> Result<
     );
 
     // Crop the image back in so we're centered where we want to be
-    let mut png_buffer = Vec::new();
-    DynamicImage::ImageRgba8(full_image)
-        .crop_imm(
-            offset_left, // X offset
-            offset_top,  // Y offset
-            tile_box.inner_size_px.0,
-            tile_box.inner_size_px.1,
-        )
-        .write_to(&mut Cursor::new(&mut png_buffer), image::ImageFormat::Png)
-        .expect("I can write a PNG");
+    let mut cropped = imageops::crop_imm(
+        &full_image,
+        offset_left, // X offset
+        offset_top,  // Y offset
+        tile_box.inner_size_px.0,
+        tile_box.inner_size_px.1,
+    )
+    .to_image();
 
-    let buffer_to_bytes = Bytes::from(png_buffer);
+    // If the tileset didn't go deep enough, scale up to the resolution we were asked for
+    if tile_box.overzoom > 0 {
+        let (width, height) = tile_box.output_size_px();
+        debug!(
+            "Overzooming by {0} levels to {1}x{2}",
+            tile_box.overzoom, width, height
+        );
+        cropped = imageops::resize(&cropped, width, height, FilterType::CatmullRom);
+    }
 
     processing_time.record(start.elapsed().as_secs_f64(), &[]);
 
-    // Return the image as Bytes
-    Ok(buffer_to_bytes)
+    Ok((cropped, used))
+}
+
+// Draws the tiles into a transparent image of the given size, with its top-left corner at
+// mosaic_origin
+fn draw_tiles(
+    tiles: HashMap<TileId, (TileSet, Tile)>,
+    tile_size: u32,
+    mosaic_origin: &WorldPixel,
+    size: (u32, u32),
+) -> Result<RgbaImage> {
+    let mut image = ImageBuffer::new(size.0, size.1);
+
+    for (tile_id, (_, tile)) in tiles {
+        let mut tile_img = tile
+            .decode()
+            .with_context(|| format!("decoding tile {:?}", tile_id))?;
+
+        // Some servers quietly hand back normal tiles when asked for retina ones (or
+        // vice-versa), so scale anything that's the wrong size to fit
+        if tile_img.dimensions() != (tile_size, tile_size) {
+            tile_img = imageops::resize(&tile_img, tile_size, tile_size, FilterType::CatmullRom);
+        }
+
+        let tile_origin = tile_id
+            .top_left()
+            .to_world_pixel(tile_size)
+            .to_image_pixel(mosaic_origin);
+        let x_offset = tile_origin.x as u32;
+        let y_offset = tile_origin.y as u32;
+
+        image.copy_from(&tile_img, x_offset, y_offset).unwrap();
+    }
+
+    Ok(image)
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use super::*;
-    use crate::coordinates::{lat_long_and_image_size_to_bounding_box, LatLong};
-    use image::GenericImageView;
+    use crate::coordinates::{
+        lat_long_and_image_size_to_bounding_box, lat_long_to_tile_coords, LatLong, TileCoordinate,
+    };
+    use crate::encoding::DEFAULT_QUALITY;
+    use image::{GenericImageView, Rgba};
+    use opentelemetry::trace::{SpanContext, SpanId, TraceFlags, TraceId, TraceState};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
     use std::env;
-    use std::fs::File;
+    use std::fs::{self, File};
     use std::io::Write;
+    use std::sync::Mutex;
+
+    // Serves tiles from memory. Tiles added with insert come first; any other tile from a
+    // tileset with a fill gets the fill, and the rest aren't there. Every fetch is logged in
+    // requests.
+    pub struct MemoryTiles {
+        config: Config,
+        tiles: HashMap<(TileSet, TileId), Tile>,
+        fills: HashMap<TileSet, Tile>,
+        pub requests: Mutex<Vec<(TileSet, TileId)>>,
+    }
+
+    impl MemoryTiles {
+        pub fn new(config: Config) -> Self {
+            MemoryTiles {
+                config,
+                tiles: HashMap::new(),
+                fills: HashMap::new(),
+                requests: Mutex::new(Vec::new()),
+            }
+        }
+
+        pub fn insert(&mut self, tileset: TileSet, id: TileId, tile: Tile) {
+            self.tiles.insert((tileset, id), tile);
+        }
+
+        pub fn fill(&mut self, tileset: TileSet, tile: Tile) {
+            self.fills.insert(tileset, tile);
+        }
+    }
+
+    impl TileSource for MemoryTiles {
+        fn config(&self) -> &Config {
+            &self.config
+        }
+
+        async fn fetch(
+            &self,
+            tileset: TileSet,
+            tile: TileId,
+            _retina: bool,
+            _source: Option<&SourceLayer>,
+            _time: Option<&str>,
+            _cx: Context,
+        ) -> Result<Option<Tile>> {
+            self.requests.lock().unwrap().push((tileset, tile));
+            Ok(self
+                .tiles
+                .get(&(tileset, tile))
+                .or_else(|| self.fills.get(&tileset))
+                .cloned())
+        }
+    }
+
+    // A PNG tile of a single colour
+    pub fn solid_tile(colour: [u8; 4]) -> Tile {
+        let mut png = Vec::new();
+        RgbaImage::from_pixel(TILE_SIZE_PX, TILE_SIZE_PX, Rgba(colour))
+            .write_to(&mut std::io::Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        Tile {
+            format: TileFormat::Png,
+            bytes: Bytes::from(png),
+        }
+    }
 
     #[tokio::test]
     async fn test_fetch_tile() {
-        let tile = (3366, 2431);
-        let zoom = 12;
+        let tile = TileId {
+            z: 12,
+            x: 3366,
+            y: 2431,
+        };
         let cx = Context::current();
 
-        // Replace the base URL with mockito’s server URL
-        let result = fetch_tile(TileSet::Osm, tile.0, tile.1, zoom, cx).await;
+        // Serve the tile from a directory, so we go through fetch_tile without the network
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("12/3366")).unwrap();
+        let expected = solid_tile([0, 128, 0, 255]);
+        fs::write(root.join("12/3366/2431.png"), &expected.bytes).unwrap();
+        let config = Config::from_json(&format!(
+            r#"{{ "tilesets": {{ "directory": {{ "directory": {{ "root": {} }} }} }} }}"#,
+            serde_json::to_string(&root).unwrap()
+        ))
+        .unwrap();
 
-        // Assert the result is Ok and contains the correct number of bytes
+        let result = fetch_tile(&config, TileSet::Directory, tile, false, None, None, cx).await;
+
+        // Assert the result is Ok and contains the tile's bytes
         assert!(result.is_ok());
-        let bytes = result.unwrap();
-        assert!(bytes.len() > 1000);
+        let tile = result.unwrap().expect("The directory has the tile");
+        assert_eq!(tile.format, TileFormat::Png);
+        assert_eq!(tile.bytes, expected.bytes);
+    }
+
+    #[test]
+    fn test_inject_trace_context() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let span_context = SpanContext::new(
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+            SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        );
+        let cx = Context::new().with_remote_span_context(span_context);
+
+        let request = inject_trace_context(awc::Client::new().get("https://example.com/"), &cx);
+        assert_eq!(
+            request.headers().get("traceparent").unwrap(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+    }
+
+    #[test]
+    fn test_detect_tile_format() {
+        let mut jpeg = Vec::new();
+        image::DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut std::io::Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+
+        // Content types with parameters are fine
+        assert_eq!(
+            TileFormat::detect("image/png; charset=binary", &[]),
+            Some(TileFormat::Png)
+        );
+        assert_eq!(
+            TileFormat::detect("Image/JPEG", &[]),
+            Some(TileFormat::Jpeg)
+        );
+
+        // Otherwise we sniff the bytes
+        assert_eq!(
+            TileFormat::detect("application/octet-stream", &jpeg),
+            Some(TileFormat::Jpeg)
+        );
+        assert_eq!(TileFormat::detect("", &jpeg), Some(TileFormat::Jpeg));
+        assert_eq!(TileFormat::detect("text/html", b"<html></html>"), None);
+
+        let tile = Tile {
+            format: TileFormat::Jpeg,
+            bytes: Bytes::from(jpeg),
+        };
+        assert_eq!(tile.decode().unwrap().dimensions(), (4, 4));
+    }
+
+    #[test]
+    fn test_tileset_url_templates() {
+        TileSet::validate_all().expect("All of our URL templates are valid");
+
+        let tile = TileId {
+            z: 12,
+            x: 2132,
+            y: 1449,
+        };
+        assert_eq!(
+            TileSet::Swisstopo
+                .url_template(None, &TileSetConfig::default())
+                .unwrap()
+                .render(tile, false, None),
+            "https://wmts.geo.admin.ch/1.0.0/ch.swisstopo.landeskarte-farbe-10/default/current/3857/12/2132/1449.png"
+        );
+        assert_eq!(
+            TileSet::Swisstopo
+                .url_template(None, &TileSetConfig::default())
+                .unwrap()
+                .render(tile, false, Some("1990")),
+            "https://wmts.geo.admin.ch/1.0.0/ch.swisstopo.landeskarte-farbe-10/default/1990/3857/12/2132/1449.png"
+        );
+        assert!(TileSet::Swisstopo.supports_time());
+        assert!(!TileSet::Osm.supports_time());
+
+        // Picking a source layer swaps in its identifier and format
+        let config = Config::default();
+        let aerial = config.source_layer(TileSet::Swisstopo, "swissimage");
+        assert_eq!(
+            TileSet::Swisstopo
+                .url_template(aerial.as_ref(), &TileSetConfig::default())
+                .unwrap()
+                .render(tile, false, None),
+            "https://wmts.geo.admin.ch/1.0.0/ch.swisstopo.swissimage/default/current/3857/12/2132/1449.jpeg"
+        );
+    }
+
+    #[test]
+    fn test_tileset_chain() {
+        assert_eq!(
+            TileSet::parse_chain("swisstopo, osm"),
+            vec![TileSet::Swisstopo, TileSet::Osm]
+        );
+        assert_eq!(TileSet::parse_chain("nope,osm"), vec![TileSet::Osm]);
+        let config = Config::default();
+
+        // Swisstopo only serves tiles over Switzerland
+        for (lat, long) in [
+            (46.948, 7.447),
+            (46.204, 6.143),
+            (46.004, 8.951),
+            (47.559, 7.588),
+        ] {
+            let tile = lat_long_to_tile_coords(&LatLong(lat, long), 12).tile_id();
+            assert!(config.serves(TileSet::Swisstopo, tile), "{lat}, {long}");
+        }
+        let paris = lat_long_to_tile_coords(&LatLong(48.857, 2.352), 12).tile_id();
+        let milan = lat_long_to_tile_coords(&LatLong(45.464, 9.190), 12).tile_id();
+        assert!(!config.serves(TileSet::Swisstopo, paris));
+        assert!(!config.serves(TileSet::Swisstopo, milan));
+        assert!(config.serves(TileSet::Osm, paris));
+
+        // ... and neither goes on forever
+        let deep = lat_long_to_tile_coords(&LatLong(46.948, 7.447), 20).tile_id();
+        assert!(!config.serves(TileSet::Swisstopo, deep));
+    }
+
+    #[test]
+    fn test_parse_layers() {
+        let config = Config::default();
+        let layers = Layer::parse_list("osm,swisstopo/hiking:0.5:multiply", &config).unwrap();
+        assert_eq!(layers[0], Layer::opaque(vec![TileSet::Osm]));
+        assert_eq!(
+            layers[1],
+            Layer {
+                tilesets: vec![TileSet::Swisstopo],
+                opacity: 0.5,
+                mode: BlendMode::Multiply,
+                time: None,
+                source: config.source_layer(TileSet::Swisstopo, "hiking"),
+            }
+        );
+
+        // Without a layer, we get the tileset's default
+        let colour = Layer::parse("swisstopo", &config).unwrap();
+        assert_eq!(
+            colour.source.unwrap().id,
+            "ch.swisstopo.landeskarte-farbe-10"
+        );
+
+        for spec in [
+            "nope",
+            "osm:1.5",
+            "osm:0.5:overlay",
+            "osm:0.5:screen:extra",
+            "osm/hiking",
+            "swisstopo/nope",
+        ] {
+            assert!(Layer::parse(spec, &config).is_err(), "{spec}");
+        }
+    }
+
+    #[test]
+    fn test_source_layer_zoom_range() {
+        let config = Config::default();
+        let mut layer = Layer::opaque(vec![TileSet::Swisstopo, TileSet::Osm]);
+        let deep = lat_long_to_tile_coords(&LatLong(46.948, 7.447), 20).tile_id();
+
+        layer.select_source(None, &config).unwrap();
+        assert_eq!(layer.max_zoom(TileSet::Swisstopo, &config), 18);
+        assert!(!layer.serves(TileSet::Swisstopo, deep, &config));
+
+        // The aerial imagery goes deeper than the maps
+        layer.select_source(Some("swissimage"), &config).unwrap();
+        assert_eq!(layer.max_zoom(TileSet::Swisstopo, &config), 20);
+        assert!(layer.serves(TileSet::Swisstopo, deep, &config));
+
+        // ... and the layer only applies to the tileset it belongs to
+        assert_eq!(layer.max_zoom(TileSet::Osm, &config), 19);
+    }
+
+    #[test]
+    fn test_select_time() {
+        let config = Config::from_json(
+            r#"{ "tilesets": { "swisstopo": { "times": { "1990": "19901231" } } } }"#,
+        )
+        .unwrap();
+        let tile = TileId {
+            z: 12,
+            x: 2132,
+            y: 1449,
+        };
+        let url = |layer: &Layer| {
+            let source = layer.source_for(TileSet::Swisstopo);
+            tile_url(
+                &config,
+                TileSet::Swisstopo,
+                tile,
+                false,
+                source,
+                layer.time.as_deref(),
+            )
+            .unwrap()
+            .0
+        };
+
+        // The current edition stays on the everyday map
+        let mut layer = Layer::opaque(vec![TileSet::Swisstopo, TileSet::Osm]);
+        layer.select_source(None, &config).unwrap();
+        assert!(layer.select_time("current", &config).unwrap());
+        assert_eq!(
+            url(&layer),
+            "https://wmts.geo.admin.ch/1.0.0/ch.swisstopo.landeskarte-farbe-10/default/current/3857/12/2132/1449.png"
+        );
+
+        // Older editions come from the time series, by the time swisstopo knows them by
+        assert!(layer.select_time("1990", &config).unwrap());
+        assert_eq!(
+            url(&layer),
+            "https://wmts.geo.admin.ch/1.0.0/ch.swisstopo.zeitreihen/default/19901231/3857/12/2132/1449.png"
+        );
+
+        // Years we don't know the edition for, and layers without older editions, are errors
+        let mut layer = Layer::opaque(vec![TileSet::Swisstopo]);
+        layer.select_source(None, &config).unwrap();
+        assert!(layer.select_time("2005", &config).is_err());
+        layer.select_source(Some("swissimage"), &config).unwrap();
+        assert!(layer.select_time("1990", &config).is_err());
+
+        // Tilesets without a time dimension leave the layer alone
+        let mut layer = Layer::opaque(vec![TileSet::Osm]);
+        assert!(!layer.select_time("1990", &config).unwrap());
+        assert_eq!(layer.time, None);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_tile_box_from_mbtiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiles.mbtiles");
+        crate::mbtiles::tests::write_test_mbtiles(&path);
+        let json = format!(
+            r#"{{ "tilesets": {{ "mbtiles": {{ "mbtiles": {} }} }} }}"#,
+            serde_json::to_string(&path).unwrap()
+        );
+        let config = Config::from_json(&json).unwrap();
+
+        // The whole world at z=1, of which the file only has the north-east tile
+        let tile_box = TileBox {
+            top_left: TileCoordinate {
+                x: 0.0,
+                y: 0.0,
+                z: 1,
+            },
+            bottom_right: TileCoordinate {
+                x: 1.9,
+                y: 1.9,
+                z: 1,
+            },
+        };
+        let tiles = fetch_tile_box(
+            &config,
+            &Layer::opaque(vec![TileSet::MbTiles]),
+            &tile_box,
+            TILE_SIZE_PX,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tiles.len(), 1);
+        let (tileset, tile) = &tiles[&TileId { z: 1, x: 1, y: 0 }];
+        assert_eq!(*tileset, TileSet::MbTiles);
+        assert_eq!(tile.format, TileFormat::Png);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_image_falls_through_chain() {
+        let bern = LatLong(46.948, 7.447);
+        let tile_box = lat_long_and_image_size_to_bounding_box(bern, 1.0, 512, TILE_SIZE_PX, 16);
+        let tile_ids = tile_box.tile_box.tile_ids();
+
+        // Swisstopo only has one of the tiles, so OSM fills in the rest
+        let mut tiles = MemoryTiles::new(Config::default());
+        tiles.insert(
+            TileSet::Swisstopo,
+            tile_ids[0],
+            solid_tile([255, 0, 0, 255]),
+        );
+        tiles.fill(TileSet::Osm, solid_tile([0, 0, 255, 255]));
+
+        let rendered = fetch_image(
+            &tiles,
+            &[Layer::opaque(vec![TileSet::Swisstopo, TileSet::Osm])],
+            &tile_box,
+            OutputFormat::Png,
+            DEFAULT_QUALITY,
+        )
+        .await
+        .unwrap();
+        assert_eq!(rendered.tilesets, vec![TileSet::Swisstopo, TileSet::Osm]);
+
+        // We asked swisstopo for everything, and OSM for everything swisstopo didn't have
+        let requests = tiles.requests.lock().unwrap();
+        assert_eq!(requests.len(), tile_ids.len() * 2 - 1);
+        assert!(!requests.contains(&(TileSet::Osm, tile_ids[0])));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_single_tile() {
+        let mut tiles = MemoryTiles::new(Config::default());
+        tiles.fill(TileSet::Swisstopo, solid_tile([255, 0, 0, 255]));
+        tiles.fill(TileSet::Osm, solid_tile([0, 0, 255, 255]));
+        let layer = Layer::opaque(vec![TileSet::Swisstopo, TileSet::Osm]);
+
+        // Bern comes from swisstopo, and Perth, which it doesn't cover, from OSM
+        let bern = lat_long_to_tile_coords(&LatLong(46.948, 7.447), 12).tile_id();
+        let (tileset, _) = fetch_single_tile(&tiles, &layer, bern, TILE_SIZE_PX)
+            .await
+            .unwrap()
+            .expect("Both tilesets have tiles in Bern");
+        assert_eq!(tileset, TileSet::Swisstopo);
+
+        let perth = lat_long_to_tile_coords(&LatLong(-31.9514, 115.8617), 12).tile_id();
+        let (tileset, tile) = fetch_single_tile(&tiles, &layer, perth, TILE_SIZE_PX)
+            .await
+            .unwrap()
+            .expect("OSM has tiles in Perth");
+        assert_eq!(tileset, TileSet::Osm);
+        assert_eq!(tile.format.content_type(), "image/png");
+        assert!(!tiles
+            .requests
+            .lock()
+            .unwrap()
+            .contains(&(TileSet::Swisstopo, perth)));
+
+        // Nobody has tiles in a layer that doesn't serve this deep
+        let deep = TileId { z: 21, ..bern };
+        let layer = Layer::opaque(vec![TileSet::Swisstopo]);
+        assert!(fetch_single_tile(&tiles, &layer, deep, TILE_SIZE_PX)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_image_with_holes() {
+        let bern = LatLong(46.948, 7.447);
+        let tile_box = lat_long_and_image_size_to_bounding_box(bern, 1.0, 512, TILE_SIZE_PX, 16);
+        let tile_ids = tile_box.tile_box.tile_ids();
+
+        // An overlay with a single tile leaves the base map showing through everywhere else
+        let mut tiles = MemoryTiles::new(Config::default());
+        tiles.fill(TileSet::Osm, solid_tile([0, 0, 255, 255]));
+        tiles.insert(
+            TileSet::Swisstopo,
+            tile_ids[0],
+            solid_tile([255, 0, 0, 255]),
+        );
+        let layers = [
+            Layer::opaque(vec![TileSet::Osm]),
+            Layer::opaque(vec![TileSet::Swisstopo]),
+        ];
+        let rendered = fetch_image(
+            &tiles,
+            &layers,
+            &tile_box,
+            OutputFormat::Png,
+            DEFAULT_QUALITY,
+        )
+        .await
+        .unwrap();
+        let image = image::load_from_memory(&rendered.bytes).unwrap();
+        let (width, height) = image.dimensions();
+        assert_eq!(
+            image.get_pixel(width - 1, height - 1),
+            Rgba([0, 0, 255, 255])
+        );
+
+        // But a base map with a hole in it is an error, rather than a transparent patch
+        let layers = [Layer::opaque(vec![TileSet::Swisstopo])];
+        let err = fetch_image(
+            &tiles,
+            &layers,
+            &tile_box,
+            OutputFormat::Png,
+            DEFAULT_QUALITY,
+        )
+        .await
+        .err()
+        .expect("swisstopo only has one of the tiles");
+        assert!(err.to_string().contains("swisstopo"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_image_outside_coverage() {
+        let tile_box = lat_long_and_image_size_to_bounding_box(
+            LatLong(-31.9514, 115.8617),
+            1.0,
+            512,
+            TILE_SIZE_PX,
+            TileSet::Swisstopo.max_zoom(),
+        );
+
+        // We should give up before making any requests
+        let err = fetch_image(
+            &Config::default(),
+            &[Layer::opaque(vec![TileSet::Swisstopo])],
+            &tile_box,
+            OutputFormat::Png,
+            DEFAULT_QUALITY,
+        )
+        .await
+        .err()
+        .expect("Perth is a long way from Switzerland");
+        let outside = err
+            .downcast_ref::<OutsideCoverage>()
+            .expect("It's a coverage error");
+        assert_eq!(outside.tilesets, vec![TileSet::Swisstopo]);
     }
 
     #[tokio::test]
@@ -294,18 +1845,35 @@ mod tests {
         let radius_km = 1.0;
 
         // Use lat_lon_and_radius_to_tile_box to calculate the bounding box for tiles
-        let tile_box = lat_long_and_image_size_to_bounding_box(point, radius_km, 1024);
+        let tile_box = lat_long_and_image_size_to_bounding_box(
+            point,
+            radius_km,
+            1024,
+            TILE_SIZE_PX,
+            TileSet::Osm.max_zoom(),
+        );
 
-        // Generate the image using fetch_image
-        let result = fetch_image(TileSet::Osm, &tile_box).await;
+        // Generate the image using fetch_image, with every OSM tile the same
+        let mut tiles = MemoryTiles::new(Config::default());
+        tiles.fill(TileSet::Osm, solid_tile([255, 255, 255, 255]));
+        let result = fetch_image(
+            &tiles,
+            &[Layer::opaque(vec![TileSet::Osm])],
+            &tile_box,
+            OutputFormat::Png,
+            DEFAULT_QUALITY,
+        )
+        .await;
         assert!(result.is_ok(), "Fetching image failed");
 
-        let image_bytes = result.unwrap();
+        let rendered = result.unwrap();
+        assert_eq!(rendered.tilesets, vec![TileSet::Osm]);
+        let image_bytes = rendered.bytes;
 
         // Load the image from the bytes to check its dimensions
         let img = image::load_from_memory(&image_bytes).expect("Failed to load image from bytes");
 
-        // Check that the image is at least 1000x1000 pixels
+        // Check that the image is at least 1000x1000 pixels, and made from our tiles
         let (width, height) = img.dimensions();
         assert!(
             width >= 1000 && height >= 1000,
@@ -313,6 +1881,14 @@ mod tests {
             width,
             height
         );
+        assert_eq!(
+            img.get_pixel(width / 2, height / 2),
+            Rgba([255, 255, 255, 255])
+        );
+        assert_eq!(
+            tiles.requests.lock().unwrap().len(),
+            tile_box.tile_box.tile_ids().len()
+        );
 
         // Create a temporary directory and file to store the image
         let dir = env::current_dir().expect("I can get my cwd");
@@ -0,0 +1,186 @@
+// ! # wms
+// !
+// ! Fetches imagery from WMS servers, which render whatever bbox they're asked for rather
+// ! than serving fixed tiles. We ask for one virtual web mercator tile at a time, so that WMS
+// ! layers mosaic, chain and blend just like XYZ tilesets do.
+// !
+
+use crate::config::encode_query_component;
+use crate::coordinates::TileId;
+use crate::tiles::TileFormat;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+// The CRSs we can ask for virtual tiles in: web mercator, under its official and legacy codes.
+// Images in any other CRS would need reprojecting to line up with the tiles around them.
+const SUPPORTED_CRS: [&str; 2] = ["EPSG:3857", "EPSG:900913"];
+
+// A WMS server and the GetMap parameters to send it, as configured in TILESETS_CONFIG
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WmsSource {
+    url: String,
+    layers: Vec<String>,
+    // One per layer, or none for their default styles
+    #[serde(default)]
+    styles: Vec<String>,
+    #[serde(default = "default_crs")]
+    crs: String,
+    #[serde(default = "default_format")]
+    format: String,
+    #[serde(default = "default_version")]
+    version: String,
+    #[serde(default)]
+    transparent: bool,
+}
+
+fn default_crs() -> String {
+    "EPSG:3857".to_string()
+}
+
+fn default_format() -> String {
+    "image/png".to_string()
+}
+
+fn default_version() -> String {
+    "1.3.0".to_string()
+}
+
+impl WmsSource {
+    // Checks that we know how to make GetMap requests with the configured parameters
+    pub fn validate(&self) -> Result<()> {
+        if self.layers.is_empty() {
+            return Err(anyhow!("WMS sources need at least one layer"));
+        }
+        if !self.styles.is_empty() && self.styles.len() != self.layers.len() {
+            return Err(anyhow!(
+                "Expected a style for each of the {} WMS layers, got {}",
+                self.layers.len(),
+                self.styles.len()
+            ));
+        }
+        if !SUPPORTED_CRS.contains(&self.crs.as_str()) {
+            return Err(anyhow!(
+                "Unsupported WMS CRS {}, expected one of {}",
+                self.crs,
+                SUPPORTED_CRS.join(", ")
+            ));
+        }
+        if self.version != "1.1.1" && self.version != "1.3.0" {
+            return Err(anyhow!(
+                "Unsupported WMS version {}, expected 1.1.1 or 1.3.0",
+                self.version
+            ));
+        }
+        self.tile_format()?;
+        Ok(())
+    }
+
+    // The format we ask the server to render in
+    pub fn tile_format(&self) -> Result<TileFormat> {
+        TileFormat::from_content_type(&self.format)
+            .ok_or_else(|| anyhow!("Unsupported WMS format {}", self.format))
+    }
+
+    // Builds the GetMap URL for a virtual tile, rendered at size_px square
+    pub fn get_map_url(&self, tile: TileId, size_px: u32) -> String {
+        let bbox: Vec<String> = tile.bbox_epsg3857().iter().map(|v| v.to_string()).collect();
+
+        // 1.3.0 renamed SRS to CRS
+        let crs_param = match self.version.as_str() {
+            "1.3.0" => "CRS",
+            _ => "SRS",
+        };
+        let size = size_px.to_string();
+        let params = [
+            ("SERVICE", "WMS"),
+            ("VERSION", &self.version),
+            ("REQUEST", "GetMap"),
+            ("LAYERS", &self.layers.join(",")),
+            ("STYLES", &self.styles.join(",")),
+            (crs_param, &self.crs),
+            ("BBOX", &bbox.join(",")),
+            ("WIDTH", &size),
+            ("HEIGHT", &size),
+            ("FORMAT", &self.format),
+            (
+                "TRANSPARENT",
+                if self.transparent { "TRUE" } else { "FALSE" },
+            ),
+        ];
+
+        let mut url = self.url.clone();
+        for (name, value) in params {
+            let separator = match url.contains('?') {
+                true if url.ends_with('?') || url.ends_with('&') => "",
+                true => "&",
+                false => "?",
+            };
+            url.push_str(&format!(
+                "{}{}={}",
+                separator,
+                name,
+                encode_query_component(value)
+            ));
+        }
+        url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(json: &str) -> WmsSource {
+        let source: WmsSource = serde_json::from_str(json).unwrap();
+        source.validate().unwrap();
+        source
+    }
+
+    #[test]
+    fn test_get_map_url() {
+        let wms = source(
+            r#"{ "url": "https://gis.example.com/wms?map=passes", "layers": ["roads", "huts"],
+                 "styles": ["", "red"], "transparent": true }"#,
+        );
+
+        assert_eq!(
+            wms.get_map_url(TileId { z: 1, x: 0, y: 0 }, 256),
+            "https://gis.example.com/wms?map=passes&SERVICE=WMS&VERSION=1.3.0&REQUEST=GetMap\
+             &LAYERS=roads%2Chuts&STYLES=%2Cred&CRS=EPSG%3A3857\
+             &BBOX=-20037508.342789244%2C0%2C0%2C20037508.342789244\
+             &WIDTH=256&HEIGHT=256&FORMAT=image%2Fpng&TRANSPARENT=TRUE"
+        );
+    }
+
+    #[test]
+    fn test_get_map_url_legacy_crs() {
+        // 1.1.1 calls it SRS, and older servers only know web mercator as EPSG:900913
+        let wms = source(
+            r#"{ "url": "https://example.com/wms", "layers": ["a"], "crs": "EPSG:900913", "version": "1.1.1" }"#,
+        );
+        let url = wms.get_map_url(TileId { z: 1, x: 1, y: 1 }, 512);
+        assert!(
+            url.contains(
+                "&SRS=EPSG%3A900913&BBOX=0%2C-20037508.342789244%2C20037508.342789244%2C0&"
+            ),
+            "{url}"
+        );
+        assert!(url.contains("&WIDTH=512&HEIGHT=512&"), "{url}");
+    }
+
+    #[test]
+    fn test_validate() {
+        for json in [
+            r#"{ "url": "https://example.com/wms", "layers": [] }"#,
+            r#"{ "url": "https://example.com/wms", "layers": ["a"], "styles": ["x", "y"] }"#,
+            r#"{ "url": "https://example.com/wms", "layers": ["a"], "crs": "EPSG:2056" }"#,
+            r#"{ "url": "https://example.com/wms", "layers": ["a"], "crs": "EPSG:4326" }"#,
+            r#"{ "url": "https://example.com/wms", "layers": ["a"], "version": "1.0.0" }"#,
+            r#"{ "url": "https://example.com/wms", "layers": ["a"], "format": "image/gif" }"#,
+        ] {
+            let wms: WmsSource = serde_json::from_str(json).unwrap();
+            assert!(wms.validate().is_err(), "{json}");
+        }
+    }
+}
@@ -0,0 +1,424 @@
+// ! # wmts
+// !
+// ! Reads the layers a WMTS server publishes from its GetCapabilities document, so that we
+// ! don't have to write out their URL templates, formats, zoom ranges and times by hand.
+// !
+
+use crate::config::Config;
+use crate::coordinates::MAX_ZOOM;
+use crate::tiles::{SourceLayer, TileFormat, TileSet};
+use anyhow::{anyhow, Result};
+use awc::http::StatusCode;
+use log::debug;
+use roxmltree::{Document, Node};
+
+// Capabilities documents for national mapping agencies list hundreds of layers, and run to
+// several megabytes
+const MAX_CAPABILITIES_BYTES: usize = 32 * 1024 * 1024;
+
+// A tile matrix set from the capabilities that we can use: one in web mercator, whose
+// matrices are identified by their zoom level
+struct MercatorMatrixSet<'a> {
+    id: &'a str,
+    zooms: Vec<u32>,
+}
+
+// Fetches the capabilities document for a tileset, sending along the same User-Agent,
+// headers and query parameters as we do with its tiles
+pub async fn fetch_capabilities(config: &Config, tileset: TileSet, url: &str) -> Result<String> {
+    let tileset_config = config.tileset(tileset);
+    let (url, redacted_url) = tileset_config.apply_query(url);
+
+    let mut request = awc::Client::new()
+        .get(&url)
+        .insert_header(("User-Agent", config.user_agent(tileset)));
+    for (name, value) in &tileset_config.headers {
+        request = request.insert_header((name.as_str(), value.expose()));
+    }
+
+    let mut response = request
+        .send()
+        .await
+        .map_err(|e| anyhow!("Failed to send request to {}: {}", redacted_url, e))?;
+    if response.status() != StatusCode::OK {
+        return Err(anyhow!(
+            "Request to {} failed with status: {}",
+            redacted_url,
+            response.status()
+        ));
+    }
+
+    let bytes = response
+        .body()
+        .limit(MAX_CAPABILITIES_BYTES)
+        .await
+        .map_err(|e| anyhow!("Failed to read response body from {}: {}", redacted_url, e))?;
+    String::from_utf8(bytes.to_vec())
+        .map_err(|e| anyhow!("Capabilities from {} aren't UTF-8: {}", redacted_url, e))
+}
+
+// Reads the layers from a GetCapabilities document, keyed by their identifiers. We can only
+// use layers with a RESTful tile URL, in a tile format we can decode, on a web mercator tile
+// matrix set; the rest are skipped.
+pub fn parse_capabilities(xml: &str) -> Result<Vec<(String, SourceLayer)>> {
+    let document = Document::parse(xml)?;
+    let contents = children(document.root_element(), "Contents")
+        .next()
+        .ok_or_else(|| anyhow!("Capabilities document has no Contents"))?;
+
+    let matrix_sets: Vec<MercatorMatrixSet> = children(contents, "TileMatrixSet")
+        .filter_map(mercator_matrix_set)
+        .collect();
+
+    let mut layers = Vec::new();
+    for layer in children(contents, "Layer") {
+        let Some(id) = child_text(layer, "Identifier") else {
+            continue;
+        };
+        match parse_layer(layer, id, &matrix_sets) {
+            Some(source) => layers.push((id.to_string(), source)),
+            None => debug!("Skipping WMTS layer {0}, we can't use any of its tiles", id),
+        }
+    }
+
+    Ok(layers)
+}
+
+fn parse_layer(
+    layer: Node,
+    id: &str,
+    matrix_sets: &[MercatorMatrixSet<'_>],
+) -> Option<SourceLayer> {
+    // The first web mercator matrix set the layer is published on, and the zoom levels it
+    // has tiles for that we can ask for
+    let (matrix_set, zooms) = children(layer, "TileMatrixSetLink").find_map(|link| {
+        let set_id = child_text(link, "TileMatrixSet")?;
+        let set = matrix_sets.iter().find(|set| set.id == set_id)?;
+
+        let limits: Vec<u32> = children(link, "TileMatrixSetLimits")
+            .flat_map(|limits| children(limits, "TileMatrixLimits"))
+            .filter_map(|limit| child_text(limit, "TileMatrix")?.parse().ok())
+            .collect();
+        let zooms: Vec<u32> = match limits.is_empty() {
+            true => set.zooms.clone(),
+            false => limits,
+        }
+        .into_iter()
+        .filter(|z| *z <= MAX_ZOOM)
+        .collect();
+        if zooms.is_empty() {
+            return None;
+        }
+        Some((set.id, zooms))
+    })?;
+
+    // The first RESTful tile URL in a format we can decode
+    let (format, template) = children(layer, "ResourceURL")
+        .filter(|url| url.attribute("resourceType") == Some("tile"))
+        .find_map(|url| {
+            let format = TileFormat::from_content_type(url.attribute("format")?)?;
+            Some((format, url.attribute("template")?))
+        })?;
+
+    let style = children(layer, "Style")
+        .find(|style| style.attribute("isDefault") == Some("true"))
+        .or_else(|| children(layer, "Style").next())
+        .and_then(|style| child_text(style, "Identifier"))
+        .unwrap_or("default");
+
+    // WMTS templates name their variables differently to ours. Time becomes {time}, and any
+    // other dimensions are pinned to their defaults. A dimension without a default leaves us
+    // nothing to ask for when the caller doesn't pick a value, so we can't use the layer.
+    let mut template = template
+        .replace("{TileMatrixSet}", matrix_set)
+        .replace("{TileMatrix}", "{z}")
+        .replace("{TileRow}", "{y}")
+        .replace("{TileCol}", "{x}")
+        .replace("{Style}", style);
+    let mut times = Vec::new();
+    for dimension in children(layer, "Dimension") {
+        let Some(name) = child_text(dimension, "Identifier") else {
+            continue;
+        };
+        let default = child_text(dimension, "Default").filter(|d| !d.is_empty())?;
+        let placeholder = format!("{{{}}}", name);
+
+        if name.eq_ignore_ascii_case("time") {
+            template = template.replace(&placeholder, "{time}");
+            times.push(default.to_string());
+            for value in children(dimension, "Value")
+                .filter_map(|v| v.text().map(str::trim))
+                .filter(|v| !v.is_empty())
+            {
+                if !times.iter().any(|t| t == value) {
+                    times.push(value.to_string());
+                }
+            }
+        } else {
+            template = template.replace(&placeholder, default);
+        }
+    }
+
+    Some(SourceLayer {
+        id: id.to_string(),
+        format,
+        min_zoom: *zooms.iter().min()?,
+        max_zoom: *zooms.iter().max()?,
+        template: Some(template),
+        times,
+    })
+}
+
+// The matrix set, if it's web mercator with matrices named for their zoom levels as in
+// OGC's GoogleMapsCompatible set
+fn mercator_matrix_set<'a>(set: Node<'a, '_>) -> Option<MercatorMatrixSet<'a>> {
+    let crs = child_text(set, "SupportedCRS")?;
+    if !crs.ends_with(":3857") && !crs.ends_with(":900913") {
+        return None;
+    }
+
+    let zooms = children(set, "TileMatrix")
+        .map(|matrix| child_text(matrix, "Identifier")?.parse().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    if zooms.is_empty() {
+        return None;
+    }
+
+    Some(MercatorMatrixSet {
+        id: child_text(set, "Identifier")?,
+        zooms,
+    })
+}
+
+// The element's children with the given local name, whatever their namespace (wmts:, ows:
+// or none at all)
+fn children<'a, 'input>(
+    node: Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children()
+        .filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+fn child_text<'a>(node: Node<'a, '_>, name: &'static str) -> Option<&'a str> {
+    children(node, name)
+        .next()
+        .and_then(|child| child.text())
+        .map(str::trim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A cut-down version of swisstopo's capabilities, with a layer on a matrix set we can't
+    // use and one in a format we can't decode
+    const CAPABILITIES: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Capabilities xmlns="http://www.opengis.net/wmts/1.0" xmlns:ows="http://www.opengis.net/ows/1.1" version="1.0.0">
+  <Contents>
+    <Layer>
+      <ows:Identifier>ch.swisstopo.swissimage</ows:Identifier>
+      <Style isDefault="true"><ows:Identifier>default</ows:Identifier></Style>
+      <Format>image/jpeg</Format>
+      <Dimension>
+        <ows:Identifier>Time</ows:Identifier>
+        <Default>current</Default>
+        <Value>current</Value>
+        <Value>2019</Value>
+        <Value>2017</Value>
+      </Dimension>
+      <TileMatrixSetLink><TileMatrixSet>2056_28</TileMatrixSet></TileMatrixSetLink>
+      <TileMatrixSetLink>
+        <TileMatrixSet>3857_21</TileMatrixSet>
+        <TileMatrixSetLimits>
+          <TileMatrixLimits><TileMatrix>8</TileMatrix></TileMatrixLimits>
+          <TileMatrixLimits><TileMatrix>20</TileMatrix></TileMatrixLimits>
+        </TileMatrixSetLimits>
+      </TileMatrixSetLink>
+      <ResourceURL format="image/jpeg" resourceType="tile"
+        template="https://wmts.geo.admin.ch/1.0.0/ch.swisstopo.swissimage/{Style}/{Time}/{TileMatrixSet}/{TileMatrix}/{TileCol}/{TileRow}.jpeg"/>
+    </Layer>
+    <Layer>
+      <ows:Identifier>ch.swisstopo.swisstlm3d-wanderwege</ows:Identifier>
+      <Style isDefault="true"><ows:Identifier>default</ows:Identifier></Style>
+      <Format>image/png</Format>
+      <TileMatrixSetLink><TileMatrixSet>3857_21</TileMatrixSet></TileMatrixSetLink>
+      <ResourceURL format="image/png" resourceType="tile"
+        template="https://wmts.geo.admin.ch/1.0.0/ch.swisstopo.swisstlm3d-wanderwege/{Style}/current/{TileMatrixSet}/{TileMatrix}/{TileCol}/{TileRow}.png"/>
+    </Layer>
+    <Layer>
+      <ows:Identifier>ch.swisstopo.lv95-only</ows:Identifier>
+      <TileMatrixSetLink><TileMatrixSet>2056_28</TileMatrixSet></TileMatrixSetLink>
+      <ResourceURL format="image/png" resourceType="tile"
+        template="https://example.com/{TileMatrix}/{TileCol}/{TileRow}.png"/>
+    </Layer>
+    <Layer>
+      <ows:Identifier>ch.swisstopo.vector</ows:Identifier>
+      <TileMatrixSetLink><TileMatrixSet>3857_21</TileMatrixSet></TileMatrixSetLink>
+      <ResourceURL format="application/vnd.mapbox-vector-tile" resourceType="tile"
+        template="https://example.com/{TileMatrix}/{TileCol}/{TileRow}.pbf"/>
+    </Layer>
+    <TileMatrixSet>
+      <ows:Identifier>2056_28</ows:Identifier>
+      <ows:SupportedCRS>urn:ogc:def:crs:EPSG:2056</ows:SupportedCRS>
+      <TileMatrix><ows:Identifier>0</ows:Identifier></TileMatrix>
+    </TileMatrixSet>
+    <TileMatrixSet>
+      <ows:Identifier>3857_21</ows:Identifier>
+      <ows:SupportedCRS>urn:ogc:def:crs:EPSG:3857</ows:SupportedCRS>
+      <TileMatrix><ows:Identifier>0</ows:Identifier></TileMatrix>
+      <TileMatrix><ows:Identifier>1</ows:Identifier></TileMatrix>
+      <TileMatrix><ows:Identifier>18</ows:Identifier></TileMatrix>
+    </TileMatrixSet>
+  </Contents>
+</Capabilities>"#;
+
+    #[test]
+    fn test_parse_capabilities() {
+        let layers = parse_capabilities(CAPABILITIES).expect("I can read the capabilities");
+        let ids: Vec<&str> = layers.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(
+            ids,
+            vec![
+                "ch.swisstopo.swissimage",
+                "ch.swisstopo.swisstlm3d-wanderwege"
+            ]
+        );
+
+        let aerial = &layers[0].1;
+        assert_eq!(aerial.format, TileFormat::Jpeg);
+        assert_eq!((aerial.min_zoom, aerial.max_zoom), (8, 20));
+        assert_eq!(aerial.times, vec!["current", "2019", "2017"]);
+        assert_eq!(
+            aerial.template.as_deref(),
+            Some("https://wmts.geo.admin.ch/1.0.0/ch.swisstopo.swissimage/default/{time}/3857_21/{z}/{x}/{y}.jpeg")
+        );
+
+        // Without limits, the layer has every zoom level of the matrix set
+        let hiking = &layers[1].1;
+        assert_eq!((hiking.min_zoom, hiking.max_zoom), (0, 18));
+        assert!(hiking.times.is_empty());
+    }
+
+    #[test]
+    fn test_capabilities_deeper_than_max_zoom() {
+        let capabilities = r#"<Capabilities xmlns="http://www.opengis.net/wmts/1.0" xmlns:ows="http://www.opengis.net/ows/1.1">
+  <Contents>
+    <Layer>
+      <ows:Identifier>deep</ows:Identifier>
+      <TileMatrixSetLink><TileMatrixSet>3857</TileMatrixSet></TileMatrixSetLink>
+      <ResourceURL format="image/png" resourceType="tile" template="https://example.com/{TileMatrix}/{TileCol}/{TileRow}.png"/>
+    </Layer>
+    <Layer>
+      <ows:Identifier>too-deep</ows:Identifier>
+      <TileMatrixSetLink>
+        <TileMatrixSet>3857</TileMatrixSet>
+        <TileMatrixSetLimits>
+          <TileMatrixLimits><TileMatrix>22</TileMatrix></TileMatrixLimits>
+          <TileMatrixLimits><TileMatrix>40</TileMatrix></TileMatrixLimits>
+        </TileMatrixSetLimits>
+      </TileMatrixSetLink>
+      <ResourceURL format="image/png" resourceType="tile" template="https://example.com/{TileMatrix}/{TileCol}/{TileRow}.png"/>
+    </Layer>
+    <TileMatrixSet>
+      <ows:Identifier>3857</ows:Identifier>
+      <ows:SupportedCRS>urn:ogc:def:crs:EPSG:3857</ows:SupportedCRS>
+      <TileMatrix><ows:Identifier>3</ows:Identifier></TileMatrix>
+      <TileMatrix><ows:Identifier>21</ows:Identifier></TileMatrix>
+      <TileMatrix><ows:Identifier>22</ows:Identifier></TileMatrix>
+      <TileMatrix><ows:Identifier>40</ows:Identifier></TileMatrix>
+    </TileMatrixSet>
+  </Contents>
+</Capabilities>"#;
+
+        // We only go as deep as we can ask for tiles, and skip layers that only start deeper
+        let layers = parse_capabilities(capabilities).unwrap();
+        assert_eq!(layers.len(), 1);
+        let (id, deep) = &layers[0];
+        assert_eq!(id, "deep");
+        assert_eq!((deep.min_zoom, deep.max_zoom), (3, MAX_ZOOM));
+    }
+
+    #[test]
+    fn test_capabilities_dimensions_without_defaults() {
+        let layer = |id: &str, dimension: &str| {
+            format!(
+                r#"<Layer>
+      <ows:Identifier>{id}</ows:Identifier>
+      {dimension}
+      <TileMatrixSetLink><TileMatrixSet>3857</TileMatrixSet></TileMatrixSetLink>
+      <ResourceURL format="image/png" resourceType="tile" template="https://example.com/{{Time}}/{{Season}}/{{TileMatrix}}/{{TileCol}}/{{TileRow}}.png"/>
+    </Layer>"#
+            )
+        };
+        let capabilities = format!(
+            r#"<Capabilities xmlns="http://www.opengis.net/wmts/1.0" xmlns:ows="http://www.opengis.net/ows/1.1">
+  <Contents>
+    {}
+    {}
+    {}
+    {}
+    <TileMatrixSet>
+      <ows:Identifier>3857</ows:Identifier>
+      <ows:SupportedCRS>urn:ogc:def:crs:EPSG:3857</ows:SupportedCRS>
+      <TileMatrix><ows:Identifier>0</ows:Identifier></TileMatrix>
+    </TileMatrixSet>
+  </Contents>
+</Capabilities>"#,
+            layer(
+                "defaults",
+                "<Dimension><ows:Identifier>Time</ows:Identifier><Default>2020</Default><Value></Value><Value>2019</Value></Dimension>
+                 <Dimension><ows:Identifier>Season</ows:Identifier><Default>winter</Default></Dimension>"
+            ),
+            layer(
+                "no-default-time",
+                "<Dimension><ows:Identifier>Time</ows:Identifier><Value>2019</Value></Dimension>"
+            ),
+            layer(
+                "empty-default-time",
+                "<Dimension><ows:Identifier>Time</ows:Identifier><Default> </Default><Value>2019</Value></Dimension>"
+            ),
+            layer(
+                "no-default-season",
+                "<Dimension><ows:Identifier>Season</ows:Identifier><Value>winter</Value></Dimension>"
+            ),
+        );
+
+        // Only the layer whose dimensions all have defaults is any use to us, and it has no
+        // empty editions
+        let layers = parse_capabilities(&capabilities).unwrap();
+        assert_eq!(layers.len(), 1);
+        let (id, defaults) = &layers[0];
+        assert_eq!(id, "defaults");
+        assert_eq!(defaults.times, vec!["2020", "2019"]);
+        assert_eq!(
+            defaults.template.as_deref(),
+            Some("https://example.com/{time}/winter/{z}/{x}/{y}.png")
+        );
+    }
+
+    #[test]
+    fn test_capabilities_make_valid_url_templates() {
+        let layers = parse_capabilities(CAPABILITIES).unwrap();
+        let tile = crate::coordinates::TileId {
+            z: 12,
+            x: 2132,
+            y: 1449,
+        };
+
+        let url = TileSet::Swisstopo
+            .url_template(Some(&layers[0].1), &crate::config::TileSetConfig::default())
+            .unwrap()
+            .render(tile, false, Some("2019"));
+        assert_eq!(
+            url,
+            "https://wmts.geo.admin.ch/1.0.0/ch.swisstopo.swissimage/default/2019/3857_21/12/2132/1449.jpeg"
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_capabilities() {
+        assert!(parse_capabilities("<Capabilities>").is_err());
+        assert!(parse_capabilities("<Capabilities/>").is_err());
+    }
+}